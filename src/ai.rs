@@ -0,0 +1,297 @@
+use crate::{
+    effect::{Damage, Effect, Move},
+    global_effect::{GlobalEffect, SpawnUnit},
+    simulation::WorldSnapshot,
+    types::{Health, Position, UnitType},
+    unit::Unit,
+};
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Distance from which a unit can hit the player in melee
+const MELEE_REACH: f32 = 20.;
+const MELEE_DAMAGE: i32 = 3;
+// Distance from which a unit keeping its distance can hit the player
+const RANGED_REACH: f32 = 120.;
+const RANGED_DAMAGE: i32 = 2;
+// Distance a unit keeping its distance tries to stay away from the player
+const KEEP_DISTANCE: f32 = 80.;
+// Distance a unit can walk in one turn
+const STEP: f32 = 40.;
+// Health at or below which a fleeing unit runs away
+const FLEE_HEALTH: i32 = 4;
+// A summoner stops summoning once this many kobolds are alive
+const MAX_KOBOLDS: usize = 6;
+const SUMMONED_HEALTH: i32 = 10;
+
+// How a computer controlled unit decides what to do on its turn
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Behavior {
+    // Walks up to the player and hits them
+    Approach,
+    // Approaches the player until its health is low, then runs away
+    Flee,
+    // Stays away from the player and hits them from a distance
+    KeepDistance,
+    // Summons kobolds next to itself while there are few of them and keeps its distance otherwise
+    Summoner,
+}
+
+// What a unit does on its turn, in the same form as the effects of a spell
+pub type Action = (Vec<(Entity, Effect)>, Vec<GlobalEffect>);
+
+impl Behavior {
+    // Decides the action of the unit from a snapshot of the world, doing nothing when either the
+    // unit or the player is not in it
+    pub fn decide(&self, actor: Entity, world: &WorldSnapshot) -> Action {
+        let (unit, player) = match (world.units.get(&actor), world.units.get(&world.player)) {
+            (Some(unit), Some(player)) => (unit, player),
+            _ => return (vec![], vec![]),
+        };
+        let position = unit.position;
+        let target = player.position;
+        let distance = position.distance(target);
+        match self {
+            Behavior::Flee if unit.health.is_some_and(|health| health <= FLEE_HEALTH) => {
+                (vec![step_away(actor, position, target, STEP)], vec![])
+            }
+            Behavior::Approach | Behavior::Flee => {
+                if distance <= MELEE_REACH {
+                    (vec![hit(world.player, MELEE_DAMAGE)], vec![])
+                } else {
+                    (
+                        vec![step_towards(actor, position, target, MELEE_REACH)],
+                        vec![],
+                    )
+                }
+            }
+            Behavior::Summoner if count_kobolds(world) < MAX_KOBOLDS => {
+                let unit = Unit {
+                    health: Health(SUMMONED_HEALTH),
+                    position: Position(
+                        position + (target - position).normalize_or_zero() * MELEE_REACH,
+                    ),
+                    unit_type: UnitType::Kobold,
+                };
+                (vec![], vec![GlobalEffect::Spawn(SpawnUnit::new(unit))])
+            }
+            Behavior::KeepDistance | Behavior::Summoner => {
+                if distance < KEEP_DISTANCE {
+                    let step = (KEEP_DISTANCE - distance).min(STEP);
+                    (vec![step_away(actor, position, target, step)], vec![])
+                } else if distance <= RANGED_REACH {
+                    (vec![hit(world.player, RANGED_DAMAGE)], vec![])
+                } else {
+                    (
+                        vec![step_towards(actor, position, target, RANGED_REACH)],
+                        vec![],
+                    )
+                }
+            }
+        }
+    }
+}
+
+fn hit(target: Entity, damage: i32) -> (Entity, Effect) {
+    (target, Effect::Damage(Damage::new(damage)))
+}
+
+// Walks towards the target, stopping at the given distance from it
+fn step_towards(actor: Entity, from: Vec2, target: Vec2, stop_at: f32) -> (Entity, Effect) {
+    let step = (from.distance(target) - stop_at).min(STEP);
+    let destination = from + (target - from).normalize_or_zero() * step;
+    (actor, Effect::Move(Move::new(Position(destination))))
+}
+
+fn step_away(actor: Entity, from: Vec2, threat: Vec2, step: f32) -> (Entity, Effect) {
+    let direction = (from - threat).try_normalize().unwrap_or(Vec2::X);
+    (
+        actor,
+        Effect::Move(Move::new(Position(from + direction * step))),
+    )
+}
+
+fn count_kobolds(world: &WorldSnapshot) -> usize {
+    world
+        .units
+        .values()
+        .filter(|unit| unit.unit_type == Some(UnitType::Kobold))
+        .count()
+}
+
+// The behavior given to units of each type when they are spawned without one
+pub struct UnitBehaviors(pub HashMap<UnitType, Behavior>);
+
+impl Default for UnitBehaviors {
+    fn default() -> Self {
+        Self(HashMap::from([(UnitType::Kobold, Behavior::Approach)]))
+    }
+}
+
+pub struct AiPlugin;
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<UnitBehaviors>()
+            .add_system(assign_behaviors_system);
+    }
+}
+
+fn assign_behaviors_system(
+    mut commands: Commands,
+    behaviors: Res<UnitBehaviors>,
+    query: Query<(Entity, &UnitType), (Added<UnitType>, Without<Behavior>)>,
+) {
+    for (entity, unit_type) in query.iter() {
+        if let Some(behavior) = behaviors.0.get(unit_type) {
+            commands.entity(entity).insert(*behavior);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // The kobold of the shared snapshot is the unit deciding what to do
+    use crate::simulation::testing::{kobold as actor, *};
+
+    // Plays the effect out on a unit with the given health and position
+    fn apply(effect: &Effect, health: i32, position: Vec2) -> (i32, Vec2) {
+        let mut health = Health(health);
+        let mut position = Position(position);
+        let mut effect = effect.clone();
+        while !effect.update(&mut health, &mut position) {}
+        (health.0, position.0)
+    }
+
+    // Where the actor ends up after the action, which must be a single move of the actor
+    fn destination(action: &Action, from: Vec2) -> Vec2 {
+        match &action.0[..] {
+            [(entity, effect @ Effect::Move(_))] if *entity == actor() && action.1.is_empty() => {
+                apply(effect, 10, from).1
+            }
+            _ => panic!("expected a move, got {:?}", action),
+        }
+    }
+
+    // The damage the action deals to the player, which must be a single hit
+    fn damage(action: &Action) -> i32 {
+        match &action.0[..] {
+            [(entity, effect @ Effect::Damage(_))]
+                if *entity == player() && action.1.is_empty() =>
+            {
+                PLAYER_HEALTH - apply(effect, PLAYER_HEALTH, Vec2::ZERO).0
+            }
+            _ => panic!("expected a hit, got {:?}", action),
+        }
+    }
+
+    fn assert_near(found: Vec2, expected: Vec2) {
+        assert!(
+            found.distance(expected) < 1.,
+            "{} is not {}",
+            found,
+            expected
+        );
+    }
+
+    #[test]
+    fn approach_steps_towards_player() {
+        let from = Vec2::new(100., 0.);
+        let action = Behavior::Approach.decide(actor(), &snapshot(from, 10));
+        assert_near(destination(&action, from), Vec2::new(100. - STEP, 0.));
+    }
+
+    #[test]
+    fn approach_stops_at_reach() {
+        let from = Vec2::new(0., MELEE_REACH + 10.);
+        let action = Behavior::Approach.decide(actor(), &snapshot(from, 10));
+        assert_near(destination(&action, from), Vec2::new(0., MELEE_REACH));
+    }
+
+    #[test]
+    fn approach_attacks_in_reach() {
+        let action = Behavior::Approach.decide(actor(), &snapshot(Vec2::new(MELEE_REACH, 0.), 10));
+        assert_eq!(damage(&action), MELEE_DAMAGE);
+    }
+
+    #[test]
+    fn flee_approaches_while_healthy() {
+        let action = Behavior::Flee.decide(actor(), &snapshot(Vec2::new(10., 0.), FLEE_HEALTH + 1));
+        assert_eq!(damage(&action), MELEE_DAMAGE);
+    }
+
+    #[test]
+    fn flee_runs_at_low_health() {
+        let from = Vec2::new(10., 0.);
+        let action = Behavior::Flee.decide(actor(), &snapshot(from, FLEE_HEALTH));
+        assert_near(destination(&action, from), Vec2::new(10. + STEP, 0.));
+    }
+
+    #[test]
+    fn keep_distance_steps_away_when_close() {
+        let from = Vec2::new(KEEP_DISTANCE - 10., 0.);
+        let action = Behavior::KeepDistance.decide(actor(), &snapshot(from, 10));
+        assert_near(destination(&action, from), Vec2::new(KEEP_DISTANCE, 0.));
+    }
+
+    #[test]
+    fn keep_distance_steps_at_most_one_step() {
+        let from = Vec2::new(0., 1.);
+        let action = Behavior::KeepDistance.decide(actor(), &snapshot(from, 10));
+        assert_near(destination(&action, from), Vec2::new(0., 1. + STEP));
+    }
+
+    #[test]
+    fn keep_distance_attacks_in_range() {
+        let action =
+            Behavior::KeepDistance.decide(actor(), &snapshot(Vec2::new(RANGED_REACH, 0.), 10));
+        assert_eq!(damage(&action), RANGED_DAMAGE);
+    }
+
+    #[test]
+    fn keep_distance_approaches_when_far() {
+        let from = Vec2::new(-RANGED_REACH - 100., 0.);
+        let action = Behavior::KeepDistance.decide(actor(), &snapshot(from, 10));
+        assert_near(destination(&action, from), from + Vec2::new(STEP, 0.));
+    }
+
+    #[test]
+    fn summoner_summons_below_cap() {
+        let from = Vec2::new(100., 0.);
+        let (effects, globals) = Behavior::Summoner.decide(actor(), &snapshot(from, 10));
+        assert!(effects.is_empty());
+        match &globals[..] {
+            [GlobalEffect::Spawn(spawn)] => {
+                assert_eq!(spawn.unit().unit_type, UnitType::Kobold);
+                assert_eq!(spawn.unit().health.0, SUMMONED_HEALTH);
+                assert_near(spawn.unit().position.0, from - Vec2::new(MELEE_REACH, 0.));
+            }
+            _ => panic!("expected a summon, got {:?}", globals),
+        }
+    }
+
+    #[test]
+    fn summoner_keeps_distance_at_cap() {
+        let from = Vec2::new(RANGED_REACH, 0.);
+        let mut world = snapshot(from, 10);
+        for i in 1..MAX_KOBOLDS {
+            world.units.insert(
+                Entity::from_raw(10 + i as u32),
+                unit(UnitType::Kobold, 10, Vec2::new(500., 0.)),
+            );
+        }
+        assert_eq!(count_kobolds(&world), MAX_KOBOLDS);
+        let action = Behavior::Summoner.decide(actor(), &world);
+        assert_eq!(damage(&action), RANGED_DAMAGE);
+    }
+
+    #[test]
+    fn does_nothing_without_player() {
+        let mut world = snapshot(Vec2::new(10., 0.), 10);
+        world.units.remove(&player());
+        let (effects, globals) = Behavior::Approach.decide(actor(), &world);
+        assert!(effects.is_empty() && globals.is_empty());
+    }
+}
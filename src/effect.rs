@@ -3,7 +3,7 @@ use std::collections::VecDeque;
 use crate::types::{Health, Position};
 use bevy::prelude::*;
 
-#[derive(Component)]
+#[derive(Component, Clone, Debug)]
 pub struct Move {
     target: Position,
 }
@@ -25,7 +25,7 @@ impl Move {
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Default)]
 pub struct MovePrep {
     pub unit: Option<Entity>,
     pub target: Option<Position>,
@@ -33,17 +33,15 @@ pub struct MovePrep {
 
 impl MovePrep {
     pub fn new() -> Self {
-        Self {
-            unit: None,
-            target: None,
-        }
+        Self::default()
     }
 
     pub fn compile(&self) -> Option<Move> {
-        self.target.clone().map(|t| Move::new(t))
+        self.target.clone().map(Move::new)
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Damage {
     damage: i32,
 }
@@ -59,25 +57,62 @@ impl Damage {
     }
 }
 
+// Frames between the ticks of effects that last over time
+const FRAMES_PER_TICK: u32 = 20;
+
+// Heals a fixed amount every tick for a number of ticks
+#[derive(Clone, Debug)]
+pub struct Regenerate {
+    amount: i32,
+    ticks: u32,
+    frames: u32,
+}
+
+impl Regenerate {
+    pub fn new(amount: i32, ticks: u32) -> Self {
+        Self {
+            amount,
+            ticks,
+            frames: 0,
+        }
+    }
+
+    pub fn update(&mut self, health: &mut Health) -> bool {
+        if self.ticks == 0 {
+            return true;
+        }
+        if self.frames == 0 {
+            health.0 += self.amount;
+            self.ticks -= 1;
+            self.frames = FRAMES_PER_TICK;
+        }
+        self.frames -= 1;
+        self.ticks == 0
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Effect {
     Move(Move),
     Damage(Damage),
+    Regenerate(Regenerate),
 }
 
 impl Effect {
-    pub fn update(&self, health: &mut Health, position: &mut Position) -> bool {
+    pub fn update(&mut self, health: &mut Health, position: &mut Position) -> bool {
         match self {
             Effect::Move(m) => m.update(position),
             Effect::Damage(d) => d.update(health),
+            Effect::Regenerate(r) => r.update(health),
         }
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Default)]
 pub struct Effects(pub VecDeque<Effect>);
 
 impl Effects {
     pub fn new() -> Self {
-        Self(VecDeque::new())
+        Self::default()
     }
 }
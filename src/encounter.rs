@@ -0,0 +1,290 @@
+use crate::{
+    ai::Behavior,
+    resources::{DefaultFont, TextureHandles},
+    spellcircuit::SpellCircuit,
+    state::GameState,
+    turn::{EffectsDone, TurnCount, TurnQueue},
+    types::{Health, Position, UnitType},
+    unit::{self, Dying, Player, Unit},
+};
+use bevy::{asset::FileAssetIo, prelude::*};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+// Encounters are stored as `<name>.encounter.ron` files in this directory of the asset folder
+pub const ENCOUNTER_DIR: &str = "encounters";
+
+// Where the asset server loads assets from, so encounters are found no matter where the game is
+// started from
+const ASSET_FOLDER: &str = "assets";
+
+// What the player has to do to win an encounter
+#[derive(Clone, Debug, Deserialize)]
+pub enum Objective {
+    KillAll,
+    Survive(u32),
+    Reach { position: Vec2, radius: f32 },
+}
+
+impl std::fmt::Display for Objective {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Objective::KillAll => write!(f, "Kill every enemy."),
+            Objective::Survive(turns) => write!(f, "Survive for {} turns.", turns),
+            Objective::Reach { position, .. } => {
+                write!(f, "Reach ({}, {}).", position.x, position.y)
+            }
+        }
+    }
+}
+
+// A unit placed in an encounter. Units without a health or behavior of their own get the default
+// health and behavior of their type.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UnitDefinition {
+    pub unit_type: UnitType,
+    pub position: Vec2,
+    #[serde(default)]
+    pub health: Option<i32>,
+    #[serde(default)]
+    pub behavior: Option<Behavior>,
+}
+
+impl UnitDefinition {
+    pub fn to_unit(&self) -> Unit {
+        Unit {
+            health: Health(
+                self.health
+                    .unwrap_or_else(|| self.unit_type.default_health()),
+            ),
+            position: Position(self.position),
+            unit_type: self.unit_type,
+        }
+    }
+}
+
+// The units an encounter starts with, the spells in the builder at the start and the objective
+// for winning it
+#[derive(Clone, Debug, Deserialize)]
+pub struct Encounter {
+    pub objective: Objective,
+    pub units: Vec<UnitDefinition>,
+    #[serde(default)]
+    pub starting_spells: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum EncounterError {
+    Io(std::io::Error),
+    Format(ron::Error),
+    // Encounters need exactly one player
+    PlayerCount(usize),
+}
+
+impl std::fmt::Display for EncounterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EncounterError::Io(err) => write!(f, "Could not read encounter: {}", err),
+            EncounterError::Format(err) => write!(f, "Encounter is malformed: {}", err),
+            EncounterError::PlayerCount(count) => {
+                write!(f, "Encounter has {} players instead of one", count)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for EncounterError {
+    fn from(err: std::io::Error) -> Self {
+        EncounterError::Io(err)
+    }
+}
+
+impl From<ron::Error> for EncounterError {
+    fn from(err: ron::Error) -> Self {
+        EncounterError::Format(err)
+    }
+}
+
+pub fn encounter_path(name: &str) -> PathBuf {
+    FileAssetIo::get_root_path()
+        .join(ASSET_FOLDER)
+        .join(ENCOUNTER_DIR)
+        .join(format!("{}.encounter.ron", name))
+}
+
+pub fn load(name: &str) -> Result<Encounter, EncounterError> {
+    let text = fs::read_to_string(encounter_path(name))?;
+    let encounter: Encounter = ron::de::from_str(&text)?;
+    let players = encounter
+        .units
+        .iter()
+        .filter(|unit| unit.unit_type == UnitType::Player)
+        .count();
+    if players != 1 {
+        return Err(EncounterError::PlayerCount(players));
+    }
+    Ok(encounter)
+}
+
+pub struct EncounterPlugin;
+
+impl Plugin for EncounterPlugin {
+    fn build(&self, app: &mut App) {
+        // The Encounter resource is inserted by whoever chooses the encounter to play
+        app.add_startup_system(setup)
+            .add_system(check_objective_system)
+            .add_system_set(SystemSet::on_enter(GameState::Victory).with_system(show_result))
+            .add_system_set(SystemSet::on_enter(GameState::Defeat).with_system(show_result))
+            .add_system_set(SystemSet::on_exit(GameState::Victory).with_system(hide_result))
+            .add_system_set(SystemSet::on_exit(GameState::Defeat).with_system(hide_result))
+            .add_system_set(SystemSet::on_update(GameState::Victory).with_system(restart_system))
+            .add_system_set(SystemSet::on_update(GameState::Defeat).with_system(restart_system));
+    }
+}
+
+fn spawn_units(
+    commands: &mut Commands,
+    texture_handles: &TextureHandles,
+    font: &DefaultFont,
+    encounter: &Encounter,
+) {
+    for unit in encounter.units.iter() {
+        let entity = unit::spawn_unit(commands, texture_handles, font.0.clone(), unit.to_unit());
+        if let Some(behavior) = unit.behavior {
+            commands.entity(entity).insert(behavior);
+        }
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    texture_handles: Res<TextureHandles>,
+    font: Res<DefaultFont>,
+    encounter: Res<Encounter>,
+) {
+    spawn_units(&mut commands, &texture_handles, &font, &encounter);
+}
+
+// Ends the encounter once the player has died or met the objective. Waits for queued effects so
+// the outcome is only decided once the last action has played out.
+fn check_objective_system(
+    encounter: Res<Encounter>,
+    effects: Res<EffectsDone>,
+    turns: Res<TurnCount>,
+    mut state: ResMut<State<GameState>>,
+    q_player: Query<&Position, (With<Player>, Without<Dying>)>,
+    q_units: Query<&UnitType, (With<Health>, Without<Dying>)>,
+) {
+    if matches!(state.current(), GameState::Victory | GameState::Defeat) || !effects.0 {
+        return;
+    }
+    let player = match q_player.get_single() {
+        Ok(player) => player.0,
+        Err(_) => {
+            state.overwrite_replace(GameState::Defeat).unwrap();
+            return;
+        }
+    };
+    let won = match encounter.objective {
+        Objective::KillAll => q_units.iter().all(|t| *t == UnitType::Player),
+        Objective::Survive(n) => turns.0 >= n,
+        Objective::Reach { position, radius } => player.distance(position) <= radius,
+    };
+    if won {
+        state.overwrite_replace(GameState::Victory).unwrap();
+    }
+}
+
+#[derive(Component)]
+struct ResultScreen;
+
+fn show_result(
+    mut commands: Commands,
+    font: Res<DefaultFont>,
+    state: Res<State<GameState>>,
+    encounter: Res<Encounter>,
+    turns: Res<TurnCount>,
+) {
+    let (title, color) = match state.current() {
+        GameState::Victory => ("Victory!", Color::GOLD),
+        _ => ("Defeat", Color::CRIMSON),
+    };
+    let lines = format!(
+        "{}\nTurns: {}\n\nPress R to restart",
+        encounter.objective, turns.0
+    );
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                position_type: PositionType::Absolute,
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::rgba(0., 0., 0., 0.6).into(),
+            ..Default::default()
+        })
+        .insert(ResultScreen)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    title,
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 60.,
+                        color,
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    lines,
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 24.,
+                        color: Color::WHITE,
+                    },
+                    TextAlignment {
+                        horizontal: HorizontalAlign::Center,
+                        ..Default::default()
+                    },
+                ),
+                ..Default::default()
+            });
+        });
+}
+
+fn hide_result(mut commands: Commands, query: Query<Entity, With<ResultScreen>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// R clears the map and starts the encounter again from its first turn
+fn restart_system(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    texture_handles: Res<TextureHandles>,
+    font: Res<DefaultFont>,
+    encounter: Res<Encounter>,
+    mut state: ResMut<State<GameState>>,
+    mut turns: ResMut<TurnCount>,
+    mut queue: ResMut<TurnQueue>,
+    // Units, corpses and selected positions all have a position
+    q_world: Query<Entity, Or<(With<Position>, With<SpellCircuit>)>>,
+) {
+    if !keys.just_pressed(KeyCode::R) {
+        return;
+    }
+    for entity in q_world.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    turns.0 = 0;
+    queue.0.clear();
+    spawn_units(&mut commands, &texture_handles, &font, &encounter);
+    state.overwrite_replace(GameState::Explore).unwrap();
+}
@@ -3,6 +3,7 @@ use crate::{
     resources::{DefaultFont, TextureHandles},
     spell::Value,
     spellcircuit::{Output, SpellCircuit},
+    state::GameState,
     unit::{self, Unit},
 };
 use bevy::prelude::*;
@@ -11,23 +12,44 @@ pub struct GlobalEffectPlugin;
 
 impl Plugin for GlobalEffectPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(execute_select_rubble)
-            .add_system(execute_spawn_unit);
+        app.add_system_set(
+            SystemSet::on_update(GameState::Casting).with_system(execute_select_rubble),
+        )
+        .add_system(execute_spawn_unit);
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Debug)]
 pub struct SelectRubble {
     output: Output,
+    // The composite nodes leading from the active circuit to the circuit the output is in
+    path: Vec<usize>,
 }
 
 impl SelectRubble {
     pub fn new(output: Output) -> Self {
-        Self { output }
+        Self {
+            output,
+            path: vec![],
+        }
+    }
+
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    pub fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    // The same selection, seen from the circuit containing the given composite node
+    pub fn nested_in(mut self, node: usize) -> Self {
+        self.path.insert(0, node);
+        self
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Debug)]
 pub struct SpawnUnit {
     unit: Unit,
 }
@@ -36,8 +58,13 @@ impl SpawnUnit {
     pub fn new(unit: Unit) -> Self {
         Self { unit }
     }
+
+    pub fn unit(&self) -> &Unit {
+        &self.unit
+    }
 }
 
+#[derive(Clone, Debug)]
 pub enum GlobalEffect {
     Select(SelectRubble),
     Spawn(SpawnUnit),
@@ -53,14 +80,7 @@ fn execute_select_rubble(
         if let Some(click) = ev_mouseclick.iter().next() {
             let circuit = &mut q_circuit.single_mut();
             let rubble = commands.spawn().insert(click.world_position.clone()).id();
-            if let Some(ref mut outputs) = circuit.nodes[select.output.node].outputs {
-                outputs[select.output.index] = Value::Target(rubble);
-            } else {
-                panic!(
-                    "Tried to replace output of unexecuted node {:?} with selected position.",
-                    select.output.node
-                );
-            };
+            circuit.set_selected(select, Value::Target(rubble));
             commands.entity(select_id).despawn();
         }
     }
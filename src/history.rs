@@ -0,0 +1,133 @@
+use crate::{
+    spellbuilder::{BuilderNode, CircuitBuilder, ConnectionError, SpellInput},
+    spellcircuit::Output,
+};
+use bevy::prelude::*;
+
+// A change to the output an input is connected to
+#[derive(Clone, Debug)]
+pub struct Rewire {
+    pub input: SpellInput,
+    pub before: Option<Output>,
+    pub after: Option<Output>,
+}
+
+// A node added to or removed from the builder, together with its card position and the
+// inputs of other nodes that were connected to it
+#[derive(Clone)]
+pub struct NodeChange {
+    pub index: usize,
+    pub node: BuilderNode,
+    pub position: Vec2,
+    pub rewires: Vec<Rewire>,
+}
+
+// A single undoable action in the spell builder
+#[derive(Clone)]
+pub enum Edit {
+    Rewire(Vec<Rewire>),
+    MoveCard { node: usize, from: Vec2, to: Vec2 },
+    InsertNode(NodeChange),
+    RemoveNode(NodeChange),
+}
+
+fn invert_rewires(rewires: &[Rewire]) -> Vec<Rewire> {
+    rewires
+        .iter()
+        .rev()
+        .map(|rewire| Rewire {
+            input: rewire.input.clone(),
+            before: rewire.after.clone(),
+            after: rewire.before.clone(),
+        })
+        .collect()
+}
+
+impl Edit {
+    // The edit that reverts this one
+    pub fn inverse(&self) -> Edit {
+        match self {
+            Edit::Rewire(rewires) => Edit::Rewire(invert_rewires(rewires)),
+            Edit::MoveCard { node, from, to } => Edit::MoveCard {
+                node: *node,
+                from: *to,
+                to: *from,
+            },
+            Edit::InsertNode(change) => Edit::RemoveNode(NodeChange {
+                rewires: invert_rewires(&change.rewires),
+                ..change.clone()
+            }),
+            Edit::RemoveNode(change) => Edit::InsertNode(NodeChange {
+                rewires: invert_rewires(&change.rewires),
+                ..change.clone()
+            }),
+        }
+    }
+
+    // Whether applying the edit changes which nodes are in the builder
+    pub fn changes_nodes(&self) -> bool {
+        matches!(self, Edit::InsertNode(_) | Edit::RemoveNode(_))
+    }
+
+    // Applies the edit to the builder and to the positions of the cards of its nodes
+    pub fn apply(
+        &self,
+        builder: &mut CircuitBuilder,
+        positions: &mut Vec<Vec2>,
+    ) -> Result<(), ConnectionError> {
+        match self {
+            Edit::Rewire(rewires) => {
+                for rewire in rewires.iter() {
+                    builder.set_input(&rewire.input, rewire.after.clone())?;
+                }
+            }
+            Edit::MoveCard { node, to, .. } => positions[*node] = *to,
+            Edit::InsertNode(change) => {
+                builder.insert_node(change.index, change.node.clone());
+                positions.insert(change.index, change.position);
+                for rewire in change.rewires.iter() {
+                    builder.set_input(&rewire.input, rewire.after.clone())?;
+                }
+            }
+            Edit::RemoveNode(change) => {
+                builder.remove_node(change.index);
+                positions.remove(change.index);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct EditHistory {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl EditHistory {
+    // Records a new edit, forgetting everything that was undone before it
+    pub fn push(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+
+    // Returns the edit that reverts the latest edit
+    pub fn undo(&mut self) -> Option<Edit> {
+        let edit = self.undo.pop()?;
+        let inverse = edit.inverse();
+        self.redo.push(edit);
+        Some(inverse)
+    }
+
+    // Returns the latest undone edit so it can be applied again
+    pub fn redo(&mut self) -> Option<Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push(edit.clone());
+        Some(edit)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
@@ -1,10 +1,27 @@
+// Bevy systems, queries and derived bundles trip these lints by design
+#![allow(
+    clippy::type_complexity,
+    clippy::forget_non_drop,
+    clippy::too_many_arguments
+)]
+
+pub mod ai;
 pub mod effect;
+pub mod encounter;
 pub mod global_effect;
+pub mod history;
 pub mod mouseclick;
+pub mod power;
 pub mod resources;
+pub mod simulation;
 pub mod spell;
+pub mod spellbook;
 pub mod spellbuilder;
 pub mod spellcircuit;
+pub mod spelldefinition;
+pub mod spellscript;
+pub mod state;
+pub mod turn;
 pub mod types;
 pub mod ui;
 pub mod unit;
@@ -1,20 +1,45 @@
 use bevy::prelude::*;
+use spell_combinator::ai::AiPlugin;
+use spell_combinator::encounter::{self, EncounterPlugin};
 use spell_combinator::global_effect::GlobalEffectPlugin;
 use spell_combinator::mouseclick::{self, MainCamera, MouseClick};
 use spell_combinator::resources::ResourcePlugin;
 use spell_combinator::spellbuilder::SpellBuilderPlugin;
 use spell_combinator::spellcircuit::CircuitPlugin;
+use spell_combinator::spelldefinition::SpellDefinitionPlugin;
+use spell_combinator::state::StatePlugin;
+use spell_combinator::turn::TurnPlugin;
 use spell_combinator::ui::UiPlugin;
 use spell_combinator::unit::UnitPlugin;
 
+// Encounter played when none is given on the command line
+const DEFAULT_ENCOUNTER: &str = "default";
+
 fn main() {
+    // The encounter to play can be given as the first argument, e.g. `cargo run -- ambush`
+    let name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ENCOUNTER.to_string());
+    let encounter = match encounter::load(&name) {
+        Ok(encounter) => encounter,
+        Err(err) => {
+            eprintln!("{}: {}", encounter::encounter_path(&name).display(), err);
+            std::process::exit(1);
+        }
+    };
     App::new()
         .insert_resource(ClearColor(Color::rgb(0.3, 0.6, 0.7)))
+        .insert_resource(encounter)
         .add_plugins(DefaultPlugins)
         .add_event::<MouseClick>()
         .add_startup_system(setup)
         .add_plugin(ResourcePlugin)
+        .add_plugin(StatePlugin)
+        .add_plugin(TurnPlugin)
+        .add_plugin(AiPlugin)
+        .add_plugin(EncounterPlugin)
         .add_plugin(UnitPlugin)
+        .add_plugin(SpellDefinitionPlugin)
         .add_plugin(CircuitPlugin)
         .add_plugin(UiPlugin)
         .add_plugin(SpellBuilderPlugin)
@@ -36,7 +36,7 @@ pub fn mouse_button_system(
 }
 
 fn compute_world_coords(ui_position: Vec2, win: &Window, camera_transform: &Transform) -> Vec2 {
-    let size = Vec2::new(win.width() as f32, win.height() as f32);
+    let size = Vec2::new(win.width(), win.height());
 
     // the default orthographic projection is in pixels from the center;
     // just undo the translation
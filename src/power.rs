@@ -0,0 +1,19 @@
+// How Power modifies the spells that take it as an input. Every point of power
+// - adds half of the base amount to damage and healing,
+// - widens the radius of area spells by RANGE_PER_POWER,
+// - makes effects that last over time last one tick longer.
+// Zero power leaves a spell at its base strength.
+
+pub const RANGE_PER_POWER: f32 = 15.;
+
+pub fn scale_amount(base: i32, power: u32) -> i32 {
+    base * (2 + power as i32) / 2
+}
+
+pub fn scale_range(base: f32, power: u32) -> f32 {
+    base + RANGE_PER_POWER * power as f32
+}
+
+pub fn scale_duration(base: u32, power: u32) -> u32 {
+    base + power
+}
@@ -36,7 +36,7 @@ impl TextureHandles {
         texture_atlases: &mut Assets<TextureAtlas>,
     ) -> TextureHandles {
         let mut map = HashMap::new();
-        for t in vec![UnitType::Player, UnitType::Kobold] {
+        for t in [UnitType::Player, UnitType::Kobold] {
             let sprite_data = sprite_data(t);
             let texture_handle = asset_server.load(sprite_data.file);
             let texture_atlas = TextureAtlas::from_grid(
@@ -89,7 +89,7 @@ fn animate_sprite_system(
         timer.tick(time.delta());
         if timer.finished() {
             let texture_atlas = texture_atlases.get(texture_atlas_handle).unwrap();
-            sprite.index = (sprite.index as usize + 1) % texture_atlas.textures.len();
+            sprite.index = (sprite.index + 1) % texture_atlas.textures.len();
         }
     }
 }
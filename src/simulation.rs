@@ -0,0 +1,333 @@
+// Executes spell circuits against a snapshot of the world without running the game, so the
+// outcome of a circuit can be checked from plain tests.
+use crate::{
+    effect::Effect,
+    global_effect::GlobalEffect,
+    spell::{Fizzle, SpellState, UnitInfo, Value},
+    spellcircuit::{Output, SpellCircuit},
+    types::{Health, Position},
+};
+use bevy::prelude::*;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Clone, Debug)]
+pub struct WorldSnapshot {
+    pub player: Entity,
+    pub units: HashMap<Entity, UnitInfo>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Completed(Value),
+    Fizzled(Fizzle),
+    // A spell asked for a position to be selected but no selections were left
+    AwaitingSelection,
+}
+
+#[derive(Debug)]
+pub struct Simulation {
+    pub outcome: Outcome,
+    // Effects and global effects in the order they were produced by the circuit
+    pub effects: Vec<(Entity, Effect)>,
+    pub globals: Vec<GlobalEffect>,
+    // Units whose health reached zero, in the order they died
+    pub died: Vec<Entity>,
+    pub units: HashMap<Entity, UnitInfo>,
+}
+
+// Applies an effect to completion, the same way the game does over several frames
+fn apply_effect(unit: &mut UnitInfo, effect: &Effect) {
+    let mut health = Health(unit.health.unwrap_or(0));
+    let mut position = Position(unit.position);
+    let mut effect = effect.clone();
+    while !effect.update(&mut health, &mut position) {}
+    if unit.health.is_some() {
+        unit.health = Some(health.0);
+    }
+    unit.position = position.0;
+}
+
+// Runs the circuit to completion. Selected positions are taken from `selections` in order.
+pub fn simulate(
+    mut circuit: SpellCircuit,
+    world: &WorldSnapshot,
+    selections: Vec<Vec2>,
+) -> Simulation {
+    let mut selections = VecDeque::from(selections);
+    let mut units = world.units.clone();
+    let mut next_id = units.keys().map(|e| e.id() + 1).max().unwrap_or(0);
+    let mut new_entity = || {
+        next_id += 1;
+        Entity::from_raw(next_id - 1)
+    };
+    let mut all_effects = vec![];
+    let mut all_globals = vec![];
+    let mut died = vec![];
+    let outcome = loop {
+        let state = SpellState {
+            output: Output::new(0, 0),
+            player: world.player,
+            element: None,
+            units: units.clone(),
+        };
+        let (effects, globals) = match circuit.execute_next_spell(&state) {
+            Ok(Some(step)) => step,
+            Ok(None) => {
+                let output = circuit.output().clone();
+                let value =
+                    circuit.nodes[output.node].outputs.as_ref().unwrap()[output.index].clone();
+                break Outcome::Completed(value);
+            }
+            Err(fizzle) => break Outcome::Fizzled(fizzle),
+        };
+        // Like in the game, effects can only target units that have health
+        if let Some((entity, _)) = effects
+            .iter()
+            .find(|(entity, _)| units.get(entity).and_then(|unit| unit.health).is_none())
+        {
+            break Outcome::Fizzled(Fizzle::MissingTarget(*entity));
+        }
+        for (entity, effect) in effects.iter() {
+            apply_effect(units.get_mut(entity).unwrap(), effect);
+        }
+        // Dead units leave the spell state, possibly leaving a corpse behind like in the game
+        let mut dead = units
+            .iter()
+            .filter(|(_, unit)| unit.health.is_some_and(|health| health <= 0))
+            .map(|(entity, _)| *entity)
+            .collect::<Vec<Entity>>();
+        dead.sort_by_key(|entity| entity.id());
+        for entity in dead {
+            let unit = units.remove(&entity).unwrap();
+            if unit.unit_type.is_some_and(|t| t.leaves_corpse()) {
+                units.insert(
+                    new_entity(),
+                    UnitInfo {
+                        health: None,
+                        position: unit.position,
+                        unit_type: None,
+                    },
+                );
+            }
+            died.push(entity);
+        }
+        let mut awaiting_selection = false;
+        for global in globals.iter() {
+            match global {
+                GlobalEffect::Select(select) => match selections.pop_front() {
+                    Some(position) => {
+                        let rubble = new_entity();
+                        units.insert(
+                            rubble,
+                            UnitInfo {
+                                health: None,
+                                position,
+                                unit_type: None,
+                            },
+                        );
+                        circuit.set_selected(select, Value::Target(rubble));
+                    }
+                    None => awaiting_selection = true,
+                },
+                GlobalEffect::Spawn(spawn) => {
+                    let unit = spawn.unit();
+                    units.insert(
+                        new_entity(),
+                        UnitInfo {
+                            health: Some(unit.health.0),
+                            position: unit.position.0,
+                            unit_type: Some(unit.unit_type),
+                        },
+                    );
+                }
+            }
+        }
+        all_effects.extend(effects);
+        all_globals.extend(globals);
+        if awaiting_selection {
+            break Outcome::AwaitingSelection;
+        }
+    };
+    Simulation {
+        outcome,
+        effects: all_effects,
+        globals: all_globals,
+        died,
+        units,
+    }
+}
+
+// Snapshots shared by the tests of modules that work on a WorldSnapshot
+#[cfg(test)]
+pub mod testing {
+    use super::WorldSnapshot;
+    use crate::{spell::UnitInfo, types::UnitType};
+    use bevy::prelude::*;
+    use std::collections::HashMap;
+
+    pub const PLAYER_HEALTH: i32 = 30;
+
+    pub fn player() -> Entity {
+        Entity::from_raw(0)
+    }
+
+    pub fn kobold() -> Entity {
+        Entity::from_raw(1)
+    }
+
+    pub fn unit(unit_type: UnitType, health: i32, position: Vec2) -> UnitInfo {
+        UnitInfo {
+            health: Some(health),
+            position,
+            unit_type: Some(unit_type),
+        }
+    }
+
+    // The player at the origin and a single kobold with the given position and health
+    pub fn snapshot(kobold_position: Vec2, kobold_health: i32) -> WorldSnapshot {
+        let units = HashMap::from([
+            (player(), unit(UnitType::Player, PLAYER_HEALTH, Vec2::ZERO)),
+            (
+                kobold(),
+                unit(UnitType::Kobold, kobold_health, kobold_position),
+            ),
+        ]);
+        WorldSnapshot {
+            player: player(),
+            units,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::*, *};
+    use crate::{spell::SpellLibrary, spellscript, types::UnitType};
+
+    fn circuit(source: &str) -> SpellCircuit {
+        spellscript::parse(source, &SpellLibrary::with_builtins())
+            .unwrap()
+            .compile()
+            .unwrap()
+    }
+
+    fn world() -> WorldSnapshot {
+        snapshot(Vec2::new(100., 0.), 10)
+    }
+
+    #[test]
+    fn constrict_damages_player() {
+        let simulation = simulate(circuit("constrict(player())"), &world(), vec![]);
+        assert!(matches!(
+            simulation.outcome,
+            Outcome::Completed(Value::Target(target)) if target == player()
+        ));
+        assert_eq!(simulation.effects.len(), 1);
+        assert_eq!(simulation.effects[0].0, player());
+        assert_eq!(simulation.units[&player()].health, Some(PLAYER_HEALTH - 3));
+        assert!(simulation.died.is_empty());
+    }
+
+    #[test]
+    fn punch_kills_player() {
+        let simulation = simulate(circuit("punch(player())"), &world(), vec![]);
+        assert!(matches!(
+            simulation.outcome,
+            Outcome::Completed(Value::Empty)
+        ));
+        assert!(matches!(
+            simulation.effects[..],
+            [(target, Effect::Damage(_))] if target == player()
+        ));
+        assert_eq!(simulation.died, vec![player()]);
+        // The player leaves no corpse
+        assert_eq!(simulation.units.len(), 1);
+    }
+
+    #[test]
+    fn fizzles_on_missing_target() {
+        let mut world = world();
+        world.units.remove(&player());
+        let simulation = simulate(
+            circuit("let p = player();\nlet _ = constrict(p);\npunch(p)"),
+            &world,
+            vec![],
+        );
+        assert!(matches!(
+            simulation.outcome,
+            Outcome::Fizzled(Fizzle::MissingTarget(target)) if target == player()
+        ));
+        assert!(simulation.effects.is_empty());
+        assert_eq!(simulation.units.len(), 1);
+    }
+
+    // A world where a kobold with little health stands next to the player
+    fn weak_kobold_world() -> WorldSnapshot {
+        snapshot(Vec2::new(10., 0.), 5)
+    }
+
+    #[test]
+    fn flurry_skips_units_that_died() {
+        let simulation = simulate(
+            circuit("let (me, pw) = introspection();\nflurry(meteor(area(me, pw)))"),
+            &weak_kobold_world(),
+            vec![],
+        );
+        assert!(matches!(
+            simulation.outcome,
+            Outcome::Completed(Value::Empty)
+        ));
+        assert_eq!(simulation.died, vec![kobold()]);
+        // Hit by the meteor and then the flurry
+        assert_eq!(
+            simulation.units[&player()].health,
+            Some(PLAYER_HEALTH - 6 - 5)
+        );
+    }
+
+    #[test]
+    fn for_all_skips_units_that_died() {
+        let simulation = simulate(
+            circuit(
+                "let (me, pw) = introspection();\nfor_all(meteor(area(me, pw)), constrict(each_unit()))",
+            ),
+            &weak_kobold_world(),
+            vec![],
+        );
+        assert!(matches!(simulation.outcome, Outcome::Completed(_)));
+        assert_eq!(simulation.died, vec![kobold()]);
+        assert_eq!(
+            simulation.units[&player()].health,
+            Some(PLAYER_HEALTH - 6 - 3)
+        );
+    }
+
+    #[test]
+    fn scout_uses_selections() {
+        let at = Vec2::new(40., -20.);
+        let simulation = simulate(circuit("spawn_cobold(scout())"), &world(), vec![at]);
+        assert!(matches!(
+            simulation.outcome,
+            Outcome::Completed(Value::Empty)
+        ));
+        assert!(matches!(
+            simulation.globals[..],
+            [GlobalEffect::Select(_), GlobalEffect::Spawn(_)]
+        ));
+        let spawned = simulation
+            .units
+            .iter()
+            .filter(|(entity, _)| !world().units.contains_key(entity))
+            .filter(|(_, unit)| unit.unit_type == Some(UnitType::Kobold))
+            .map(|(_, unit)| unit.position)
+            .collect::<Vec<Vec2>>();
+        assert_eq!(spawned, vec![at]);
+    }
+
+    #[test]
+    fn scout_awaits_selection() {
+        let simulation = simulate(circuit("spawn_cobold(scout())"), &world(), vec![]);
+        assert!(matches!(simulation.outcome, Outcome::AwaitingSelection));
+        assert_eq!(simulation.units.len(), 2);
+    }
+}
@@ -1,61 +1,268 @@
 use crate::{
-    effect::{Damage, Effect},
+    effect::{Damage, Effect, Regenerate},
     global_effect::{GlobalEffect, SelectRubble, SpawnUnit},
-    spellcircuit::Output,
+    power::{scale_amount, scale_duration, scale_range},
+    spellcircuit::{Composite, Output},
+    spelldefinition::SpellDefinition,
     types::{Health, Position, UnitType},
     unit::Unit,
 };
 use bevy::prelude::*;
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
 
 #[derive(Clone, Debug)]
 pub enum Value {
     Target(Entity),
     Power(u32),
     Empty,
+    Targets(Vec<Entity>),
+    // A circle on the map. Spells affecting an area affect the units inside it when they are cast.
+    Area { center: Vec2, radius: f32 },
 }
 
-#[derive(Clone)]
+// The type of a spell input or output. Connections in a circuit must match types.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub enum Type {
+    Target,
+    Power,
+    Empty,
+    Targets,
+    Area,
+    // Only used for inputs, which then accept values of every type
+    Any,
+}
+
+impl Type {
+    // Whether an input of this type can be connected to an output of the found type
+    pub fn accepts(self, found: Type) -> bool {
+        self == Type::Any || self == found
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Type::Target => "Target",
+            Type::Power => "Power",
+            Type::Empty => "Empty",
+            Type::Targets => "Targets",
+            Type::Area => "Area",
+            Type::Any => "Any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct UnitInfo {
     pub health: Option<i32>,
     pub position: Vec2,
+    // None for things that are not units, like selected positions
+    pub unit_type: Option<UnitType>,
 }
 
 #[derive(Clone)]
 pub struct SpellState {
     pub output: Output,
+    // The value the innermost loop being executed is currently at
+    pub element: Option<Value>,
     pub player: Entity,
     pub units: HashMap<Entity, UnitInfo>,
 }
 
-type SpellResult = (Vec<Value>, Vec<(Entity, Effect)>, Vec<GlobalEffect>);
+// Why a spell failed to take effect. A fizzled spell stops the rest of its circuit.
+#[derive(Clone, Debug)]
+pub enum Fizzle {
+    BadInputs(Vec<Value>),
+    MissingTarget(Entity),
+}
+
+impl std::fmt::Display for Fizzle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Fizzle::BadInputs(inputs) => write!(f, "Spell got bad inputs: {:?}", inputs),
+            Fizzle::MissingTarget(entity) => write!(f, "Target {:?} no longer exists", entity),
+        }
+    }
+}
+
+type SpellOutput = (Vec<Value>, Vec<(Entity, Effect)>, Vec<GlobalEffect>);
+pub type SpellResult = Result<SpellOutput, Fizzle>;
+
+// Picks which branch of a branching spell to take from the values of its condition inputs
+pub type Condition = fn(&SpellState, &[Value]) -> Result<usize, Fizzle>;
+
+// A spell whose first `conditions` inputs decide which one of the remaining inputs becomes its
+// output. Only the chosen input is evaluated by the circuit.
+#[derive(Clone, Copy)]
+pub struct Branch {
+    pub conditions: usize,
+    pub condition: Condition,
+}
+
+// Lists the values the body of a loop is executed for, from the values of its condition inputs
+pub type LoopItems = fn(&SpellState, &[Value]) -> Result<Vec<Value>, Fizzle>;
+
+// A spell whose first `conditions` inputs decide how many times its last input, the body, is
+// executed. The circuit executes the body once for every item, but never more than
+// MAX_ITERATIONS times, and outputs the number of iterations as Power.
+#[derive(Clone, Copy)]
+pub struct Loop {
+    pub conditions: usize,
+    pub items: LoopItems,
+}
+
+pub const MAX_ITERATIONS: usize = 5;
+
+// How a spell computes its outputs and effects from its inputs
+#[derive(Clone)]
+pub enum SpellFunction {
+    Native(fn(&SpellState, Vec<Value>) -> SpellResult),
+    Defined(Arc<SpellDefinition>),
+    Branch(Branch),
+    Loop(Loop),
+    Composite(Arc<Composite>),
+}
+
+impl SpellFunction {
+    pub fn call(&self, s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+        match self {
+            SpellFunction::Native(function) => function(s, inputs),
+            SpellFunction::Defined(definition) => definition.cast(s, inputs),
+            // These decide which spells of their circuit run, so only the circuit can execute them
+            SpellFunction::Branch(_) | SpellFunction::Loop(_) | SpellFunction::Composite(_) => {
+                Err(Fizzle::BadInputs(inputs))
+            }
+        }
+    }
+}
+
+// What is shown to the player on the card of a spell
+#[derive(Clone, Debug)]
+pub struct SpellInfo {
+    pub display_name: String,
+    pub description: String,
+    pub input_labels: Vec<String>,
+    pub output_labels: Vec<String>,
+}
+
+impl SpellInfo {
+    // Uses the name of the spell and the names of the port types when nothing better is known
+    fn default_for(name: &str, inputs: &[Type], outputs: &[Type]) -> Self {
+        Self {
+            display_name: name.to_string(),
+            description: String::new(),
+            input_labels: inputs.iter().map(|t| t.to_string()).collect(),
+            output_labels: outputs.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Spell {
-    pub num_inputs: usize,
-    pub num_outputs: usize,
-    pub function: fn(&SpellState, Vec<Value>) -> SpellResult,
+    pub name: String,
+    pub inputs: Vec<Type>,
+    pub outputs: Vec<Type>,
+    pub function: SpellFunction,
+    pub info: SpellInfo,
+    // Mana needed to cast the spell as part of a circuit
+    pub cost: u32,
 }
 
 impl Spell {
     pub fn new(
-        num_inputs: usize,
-        num_outputs: usize,
+        name: &str,
+        inputs: Vec<Type>,
+        outputs: Vec<Type>,
         function: fn(&SpellState, Vec<Value>) -> SpellResult,
     ) -> Self {
         Self {
-            num_inputs,
-            num_outputs,
-            function,
+            name: name.to_string(),
+            info: SpellInfo::default_for(name, &inputs, &outputs),
+            inputs,
+            outputs,
+            function: SpellFunction::Native(function),
+            cost: 0,
+        }
+    }
+
+    pub fn from_definition(definition: SpellDefinition) -> Self {
+        let outputs = definition.output_types();
+        let mut info = SpellInfo::default_for(&definition.name, &definition.inputs, &outputs);
+        if let Some(display_name) = &definition.display_name {
+            info.display_name = display_name.clone();
+        }
+        info.description = definition.description.clone();
+        // Labels missing from the definition keep the name of their type
+        for (label, given) in info.input_labels.iter_mut().zip(&definition.input_labels) {
+            *label = given.clone();
+        }
+        for (label, given) in info.output_labels.iter_mut().zip(&definition.output_labels) {
+            *label = given.clone();
+        }
+        Self {
+            name: definition.name.clone(),
+            inputs: definition.inputs.clone(),
+            outputs,
+            cost: definition.cost,
+            function: SpellFunction::Defined(Arc::new(definition)),
+            info,
         }
     }
+
+    pub fn with_info(
+        mut self,
+        display_name: &str,
+        description: &str,
+        input_labels: &[&str],
+        output_labels: &[&str],
+    ) -> Self {
+        // Cards show a label for every port
+        debug_assert_eq!(
+            input_labels.len(),
+            self.inputs.len(),
+            "inputs of {}",
+            self.name
+        );
+        debug_assert_eq!(
+            output_labels.len(),
+            self.outputs.len(),
+            "outputs of {}",
+            self.name
+        );
+        self.info = SpellInfo {
+            display_name: display_name.to_string(),
+            description: description.to_string(),
+            input_labels: input_labels.iter().map(|l| l.to_string()).collect(),
+            output_labels: output_labels.iter().map(|l| l.to_string()).collect(),
+        };
+        self
+    }
+
+    pub fn with_cost(mut self, cost: u32) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    pub fn has_signature_of(&self, other: &Spell) -> bool {
+        self.inputs == other.inputs && self.outputs == other.outputs
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.outputs.len()
+    }
 }
 
 fn player(s: &SpellState, _inputs: Vec<Value>) -> SpellResult {
     let outputs = vec![Value::Target(s.player)];
     let effects = vec![];
     let globals = vec![];
-    (outputs, effects, globals)
+    Ok((outputs, effects, globals))
 }
 
 fn punch(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
@@ -63,9 +270,9 @@ fn punch(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
         let outputs = vec![Value::Empty];
         let effects = vec![(entity, Effect::Damage(Damage::new(69)))];
         let globals = vec![];
-        (outputs, effects, globals)
+        Ok((outputs, effects, globals))
     } else {
-        panic!("Bad inputs to punch: {:?}", inputs);
+        Err(Fizzle::BadInputs(inputs))
     }
 }
 
@@ -73,14 +280,14 @@ fn introspection(s: &SpellState, _inputs: Vec<Value>) -> SpellResult {
     let outputs = vec![Value::Target(s.player), Value::Power(2)];
     let effects = vec![];
     let globals = vec![];
-    (outputs, effects, globals)
+    Ok((outputs, effects, globals))
 }
 
 fn air(_s: &SpellState, _inputs: Vec<Value>) -> SpellResult {
     let outputs = vec![Value::Power(0)];
     let effects = vec![];
     let globals = vec![];
-    (outputs, effects, globals)
+    Ok((outputs, effects, globals))
 }
 
 fn constrict(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
@@ -88,22 +295,23 @@ fn constrict(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
         let outputs = vec![Value::Target(entity)];
         let effects = vec![(entity, Effect::Damage(Damage::new(3)))];
         let globals = vec![];
-        (outputs, effects, globals)
+        Ok((outputs, effects, globals))
     } else {
-        panic!("Bad inputs to constrict: {:?}", inputs);
+        Err(Fizzle::BadInputs(inputs))
     }
 }
 
 fn draw_life(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
     if let Value::Target(entity) = inputs[0] {
-        let damage = s.units.get(&entity).unwrap().health.unwrap_or(0) / 10;
+        let target = s.units.get(&entity).ok_or(Fizzle::MissingTarget(entity))?;
+        let damage = target.health.unwrap_or(0) / 10;
         let damage = if damage >= 0 { damage } else { 0 };
         let outputs = vec![Value::Power(damage as u32)];
         let effects = vec![(entity, Effect::Damage(Damage::new(damage)))];
         let globals = vec![];
-        (outputs, effects, globals)
+        Ok((outputs, effects, globals))
     } else {
-        panic!("Bad inputs to draw life: {:?}", inputs);
+        Err(Fizzle::BadInputs(inputs))
     }
 }
 
@@ -111,7 +319,7 @@ fn scout(s: &SpellState, _inputs: Vec<Value>) -> SpellResult {
     let outputs = vec![Value::Empty];
     let effects = vec![];
     let globals = vec![GlobalEffect::Select(SelectRubble::new(s.output.clone()))];
-    (outputs, effects, globals)
+    Ok((outputs, effects, globals))
 }
 
 fn spawn_cobold(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
@@ -119,7 +327,7 @@ fn spawn_cobold(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
         let position = s
             .units
             .get(&entity)
-            .expect("Target of spawn cobold does not exist!")
+            .ok_or(Fizzle::MissingTarget(entity))?
             .position;
         let cobold = Unit {
             health: Health(10),
@@ -129,42 +337,671 @@ fn spawn_cobold(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
         let outputs = vec![Value::Empty];
         let effects = vec![];
         let globals = vec![GlobalEffect::Spawn(SpawnUnit::new(cobold))];
-        (outputs, effects, globals)
+        Ok((outputs, effects, globals))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn empowered_punch(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Target(entity), Value::Power(power)] = inputs[..] {
+        let damage = scale_amount(10, power);
+        let outputs = vec![Value::Empty];
+        let effects = vec![(entity, Effect::Damage(Damage::new(damage)))];
+        let globals = vec![];
+        Ok((outputs, effects, globals))
     } else {
-        panic!("Bad inputs to spawn cobold: {:?}", inputs);
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+// Radius of a fireball cast without power
+const FIREBALL_RADIUS: f32 = 30.;
+
+// Every unit with health within the radius of the center, nearest first
+fn units_around(s: &SpellState, center: Vec2, radius: f32) -> Vec<Entity> {
+    let mut units = s
+        .units
+        .iter()
+        .filter(|(_, unit)| unit.health.is_some() && unit.position.distance(center) <= radius)
+        .map(|(entity, unit)| (unit.position.distance(center), *entity))
+        .collect::<Vec<(f32, Entity)>>();
+    units.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    units.into_iter().map(|(_, entity)| entity).collect()
+}
+
+fn fireball(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Target(entity), Value::Power(power)] = inputs[..] {
+        let center = s
+            .units
+            .get(&entity)
+            .ok_or(Fizzle::MissingTarget(entity))?
+            .position;
+        let radius = scale_range(FIREBALL_RADIUS, power);
+        let damage = scale_amount(4, power);
+        let outputs = vec![Value::Empty];
+        let effects = units_around(s, center, radius)
+            .into_iter()
+            .map(|entity| (entity, Effect::Damage(Damage::new(damage))))
+            .collect();
+        let globals = vec![];
+        Ok((outputs, effects, globals))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn heal(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Target(entity), Value::Power(power)] = inputs[..] {
+        let amount = scale_amount(2, power);
+        let ticks = scale_duration(2, power);
+        let outputs = vec![Value::Target(entity)];
+        let effects = vec![(entity, Effect::Regenerate(Regenerate::new(amount, ticks)))];
+        let globals = vec![];
+        Ok((outputs, effects, globals))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn unit_info<'a>(s: &'a SpellState, value: &Value) -> Result<&'a UnitInfo, Fizzle> {
+    match value {
+        Value::Target(entity) => s.units.get(entity).ok_or(Fizzle::MissingTarget(*entity)),
+        _ => Err(Fizzle::BadInputs(vec![value.clone()])),
+    }
+}
+
+fn health_below(s: &SpellState, inputs: &[Value]) -> Result<usize, Fizzle> {
+    if let [subject, Value::Power(threshold)] = inputs {
+        let below = unit_info(s, subject)?
+            .health
+            .is_some_and(|health| health < *threshold as i32);
+        Ok(if below { 0 } else { 1 })
+    } else {
+        Err(Fizzle::BadInputs(inputs.to_vec()))
+    }
+}
+
+fn is_kobold(s: &SpellState, inputs: &[Value]) -> Result<usize, Fizzle> {
+    let kobold = unit_info(s, &inputs[0])?.unit_type == Some(UnitType::Kobold);
+    Ok(if kobold { 0 } else { 1 })
+}
+
+fn nearest(s: &SpellState, inputs: &[Value]) -> Result<usize, Fizzle> {
+    let from = unit_info(s, &inputs[0])?.position;
+    let a = unit_info(s, &inputs[1])?.position;
+    let b = unit_info(s, &inputs[2])?.position;
+    Ok(if from.distance(a) <= from.distance(b) {
+        0
+    } else {
+        1
+    })
+}
+
+fn repeat_items(_s: &SpellState, inputs: &[Value]) -> Result<Vec<Value>, Fizzle> {
+    if let [Value::Power(count)] = inputs {
+        Ok((0..*count).take(MAX_ITERATIONS).map(Value::Power).collect())
+    } else {
+        Err(Fizzle::BadInputs(inputs.to_vec()))
+    }
+}
+
+// Radius of areas, and of the searches of for_each and nearby, without power
+const AREA_RADIUS: f32 = 40.;
+
+fn units_near(s: &SpellState, inputs: &[Value]) -> Result<Vec<Value>, Fizzle> {
+    if let [center, Value::Power(power)] = inputs {
+        let center = unit_info(s, center)?.position;
+        let radius = scale_range(AREA_RADIUS, *power);
+        Ok(units_around(s, center, radius)
+            .into_iter()
+            .map(Value::Target)
+            .collect())
+    } else {
+        Err(Fizzle::BadInputs(inputs.to_vec()))
+    }
+}
+
+// Lists of targets may hold units that died since the list was made. Spells acting on a list skip
+// those instead of fizzling.
+fn alive(s: &SpellState, entity: &Entity) -> bool {
+    s.units.contains_key(entity)
+}
+
+fn each_target(s: &SpellState, inputs: &[Value]) -> Result<Vec<Value>, Fizzle> {
+    if let [Value::Targets(targets)] = inputs {
+        Ok(targets
+            .iter()
+            .filter(|entity| alive(s, entity))
+            .copied()
+            .map(Value::Target)
+            .collect())
+    } else {
+        Err(Fizzle::BadInputs(inputs.to_vec()))
+    }
+}
+
+fn nearby(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [center, Value::Power(power)] = &inputs[..] {
+        let center = unit_info(s, center)?.position;
+        let targets = units_around(s, center, scale_range(AREA_RADIUS, *power));
+        Ok((vec![Value::Targets(targets)], vec![], vec![]))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn all_kobolds(s: &SpellState, _inputs: Vec<Value>) -> SpellResult {
+    let mut kobolds = s
+        .units
+        .iter()
+        .filter(|(_, unit)| unit.unit_type == Some(UnitType::Kobold))
+        .map(|(entity, _)| *entity)
+        .collect::<Vec<Entity>>();
+    kobolds.sort();
+    Ok((vec![Value::Targets(kobolds)], vec![], vec![]))
+}
+
+fn area(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [center, Value::Power(power)] = &inputs[..] {
+        let center = unit_info(s, center)?.position;
+        let radius = scale_range(AREA_RADIUS, *power);
+        Ok((vec![Value::Area { center, radius }], vec![], vec![]))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn flurry(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Targets(targets)] = &inputs[..] {
+        let mut effects = vec![];
+        for entity in targets.iter().filter(|entity| alive(s, entity)) {
+            effects.push((*entity, Effect::Damage(Damage::new(5))));
+        }
+        Ok((vec![Value::Empty], effects, vec![]))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn meteor(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Area { center, radius }] = inputs[..] {
+        let hit = units_around(s, center, radius);
+        let effects = hit
+            .iter()
+            .map(|entity| (*entity, Effect::Damage(Damage::new(6))))
+            .collect();
+        Ok((vec![Value::Targets(hit)], effects, vec![]))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn count(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    if let [Value::Targets(targets)] = &inputs[..] {
+        Ok((vec![Value::Power(targets.len() as u32)], vec![], vec![]))
+    } else {
+        Err(Fizzle::BadInputs(inputs))
+    }
+}
+
+fn argument(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    Err(Fizzle::BadInputs(inputs))
+}
+
+fn gather(_s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    Ok((inputs, vec![], vec![]))
+}
+
+fn each_unit(s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+    match s.element {
+        Some(Value::Target(entity)) => Ok((vec![Value::Target(entity)], vec![], vec![])),
+        _ => Err(Fizzle::BadInputs(inputs)),
     }
 }
 
 impl Spell {
+    pub fn repeated(name: &str, conditions: Vec<Type>, items: LoopItems) -> Self {
+        let num_conditions = conditions.len();
+        let mut inputs = conditions;
+        inputs.push(Type::Any);
+        Self {
+            name: name.to_string(),
+            info: SpellInfo::default_for(name, &inputs, &[Type::Power]),
+            inputs,
+            outputs: vec![Type::Power],
+            function: SpellFunction::Loop(Loop {
+                conditions: num_conditions,
+                items,
+            }),
+            cost: 0,
+        }
+    }
+
+    pub fn repeat() -> Self {
+        Self::repeated("repeat", vec![Type::Power], repeat_items)
+            .with_info(
+                "Repeat",
+                &format!(
+                    "Casts the body as many times as the power, at most {}. Outputs the number of casts.",
+                    MAX_ITERATIONS
+                ),
+                &["times", "body"],
+                &["casts"],
+            )
+            .with_cost(1)
+    }
+
+    pub fn for_each() -> Self {
+        Self::repeated("for_each", vec![Type::Target, Type::Power], units_near)
+            .with_info(
+                "For Each",
+                &format!(
+                    "Casts the body for each of the {} nearest units around the center. Use Each Unit in the body.",
+                    MAX_ITERATIONS
+                ),
+                &["center", "power", "body"],
+                &["casts"],
+            )
+            .with_cost(2)
+    }
+
+    pub fn for_all() -> Self {
+        Self::repeated("for_all", vec![Type::Targets], each_target)
+            .with_info(
+                "For All",
+                &format!(
+                    "Casts the body for each of the first {} targets. Use Each Unit in the body.",
+                    MAX_ITERATIONS
+                ),
+                &["targets", "body"],
+                &["casts"],
+            )
+            .with_cost(2)
+    }
+
+    pub fn nearby() -> Self {
+        Self::new(
+            "nearby",
+            vec![Type::Target, Type::Power],
+            vec![Type::Targets],
+            nearby,
+        )
+        .with_info(
+            "Nearby",
+            "Every unit around the center, nearest first. Power widens the search.",
+            &["center", "power"],
+            &["units"],
+        )
+        .with_cost(1)
+    }
+
+    pub fn all_kobolds() -> Self {
+        Self::new("all_kobolds", vec![], vec![Type::Targets], all_kobolds)
+            .with_info("All Kobolds", "Every kobold on the map.", &[], &["kobolds"])
+            .with_cost(2)
+    }
+
+    pub fn area() -> Self {
+        Self::new(
+            "area",
+            vec![Type::Target, Type::Power],
+            vec![Type::Area],
+            area,
+        )
+        .with_info(
+            "Area",
+            "A circle around the center. Power widens it.",
+            &["center", "power"],
+            &["area"],
+        )
+        .with_cost(1)
+    }
+
+    pub fn flurry() -> Self {
+        Self::new("flurry", vec![Type::Targets], vec![Type::Empty], flurry)
+            .with_info(
+                "Flurry",
+                "Deals 5 damage to each of the targets.",
+                &["targets"],
+                &["done"],
+            )
+            .with_cost(3)
+    }
+
+    pub fn meteor() -> Self {
+        Self::new("meteor", vec![Type::Area], vec![Type::Targets], meteor)
+            .with_info(
+                "Meteor",
+                "Deals 6 damage to every unit in the area and outputs the units hit.",
+                &["area"],
+                &["hit"],
+            )
+            .with_cost(4)
+    }
+
+    pub fn count() -> Self {
+        Self::new("count", vec![Type::Targets], vec![Type::Power], count).with_info(
+            "Count",
+            "One power for each of the targets.",
+            &["targets"],
+            &["power"],
+        )
+    }
+
+    // Stands in for an input of a composite spell. Its output is filled in before the circuit runs.
+    pub fn argument(t: Type) -> Self {
+        Self::new("argument", vec![], vec![t], argument)
+    }
+
+    // Passes its inputs on as outputs, collecting the outputs of a composite spell
+    pub fn gather(types: Vec<Type>) -> Self {
+        Self::new("gather", types.clone(), types, gather)
+    }
+
+    // Packages a circuit as a single spell
+    pub fn composite(
+        name: &str,
+        inputs: Vec<Type>,
+        outputs: Vec<Type>,
+        composite: Composite,
+    ) -> Self {
+        let description = format!(
+            "A circuit of {} spells.",
+            composite.circuit.nodes.len() - composite.arguments.len() - 1
+        );
+        Self {
+            name: name.to_string(),
+            info: SpellInfo {
+                description,
+                ..SpellInfo::default_for(name, &inputs, &outputs)
+            },
+            inputs,
+            outputs,
+            cost: composite.circuit.cost(),
+            function: SpellFunction::Composite(Arc::new(composite)),
+        }
+    }
+
+    pub fn each_unit() -> Self {
+        Self::new("each_unit", vec![], vec![Type::Target], each_unit).with_info(
+            "Each Unit",
+            "The unit the innermost For Each is currently casting its body for.",
+            &[],
+            &["unit"],
+        )
+    }
+
+    pub fn branch(
+        name: &str,
+        conditions: Vec<Type>,
+        branches: usize,
+        condition: Condition,
+    ) -> Self {
+        let mut inputs = conditions;
+        let num_conditions = inputs.len();
+        inputs.extend(vec![Type::Target; branches]);
+        Self {
+            name: name.to_string(),
+            info: SpellInfo::default_for(name, &inputs, &[Type::Target]),
+            inputs,
+            outputs: vec![Type::Target],
+            function: SpellFunction::Branch(Branch {
+                conditions: num_conditions,
+                condition,
+            }),
+            cost: 0,
+        }
+    }
+
+    pub fn if_health_below() -> Self {
+        Self::branch(
+            "if_health_below",
+            vec![Type::Target, Type::Power],
+            2,
+            health_below,
+        )
+        .with_info(
+            "If Health Below",
+            "Continues with then if the unit has less health than the threshold, otherwise with else.",
+            &["unit", "threshold", "then", "else"],
+            &["result"],
+        )
+        .with_cost(1)
+    }
+
+    pub fn if_kobold() -> Self {
+        Self::branch("if_kobold", vec![Type::Target], 2, is_kobold)
+            .with_info(
+                "If Kobold",
+                "Continues with then if the unit is a kobold, otherwise with else.",
+                &["unit", "then", "else"],
+                &["result"],
+            )
+            .with_cost(1)
+    }
+
+    pub fn choose_nearest() -> Self {
+        Self::branch(
+            "choose_nearest",
+            vec![Type::Target, Type::Target, Type::Target],
+            2,
+            nearest,
+        )
+        .with_info(
+            "Choose Nearest",
+            "Continues with the branch of whichever of a and b is nearest to from.",
+            &["from", "a", "b", "if a", "if b"],
+            &["result"],
+        )
+        .with_cost(1)
+    }
+
     pub fn player() -> Self {
-        Self::new(0, 1, player)
+        Self::new("player", vec![], vec![Type::Target], player).with_info(
+            "Player",
+            "The caster of the spell.",
+            &[],
+            &["you"],
+        )
     }
 
     pub fn punch() -> Self {
-        Self::new(1, 1, punch)
+        Self::new("punch", vec![Type::Target], vec![Type::Empty], punch)
+            .with_info(
+                "Punch",
+                "Deals 69 damage to the target.",
+                &["target"],
+                &["done"],
+            )
+            .with_cost(3)
     }
 
     pub fn introspection() -> Self {
-        Self::new(0, 1, introspection)
+        Self::new(
+            "introspection",
+            vec![],
+            vec![Type::Target, Type::Power],
+            introspection,
+        )
+        .with_info(
+            "Introspection",
+            "Looks inward for the caster and 2 power.",
+            &[],
+            &["you", "power"],
+        )
+        .with_cost(1)
     }
 
     pub fn air() -> Self {
-        Self::new(0, 1, air)
+        Self::new("air", vec![], vec![Type::Power], air).with_info(
+            "Air",
+            "Nothing but 0 power.",
+            &[],
+            &["power"],
+        )
     }
 
     pub fn constrict() -> Self {
-        Self::new(1, 1, constrict)
+        Self::new(
+            "constrict",
+            vec![Type::Target],
+            vec![Type::Target],
+            constrict,
+        )
+        .with_info(
+            "Constrict",
+            "Deals 3 damage to the target and passes it on.",
+            &["target"],
+            &["target"],
+        )
+        .with_cost(1)
     }
 
     pub fn draw_life() -> Self {
-        Self::new(1, 1, draw_life)
+        Self::new(
+            "draw_life",
+            vec![Type::Target],
+            vec![Type::Power],
+            draw_life,
+        )
+        .with_info(
+            "Draw Life",
+            "Drains a tenth of the target's health as power.",
+            &["target"],
+            &["power"],
+        )
+        .with_cost(2)
     }
 
     pub fn scout() -> Self {
-        Self::new(0, 1, scout)
+        Self::new("scout", vec![], vec![Type::Target], scout)
+            .with_info(
+                "Scout",
+                "Targets a position chosen on the map.",
+                &[],
+                &["position"],
+            )
+            .with_cost(1)
     }
 
     pub fn spawn_cobold() -> Self {
-        Self::new(1, 1, spawn_cobold)
+        Self::new(
+            "spawn_cobold",
+            vec![Type::Target],
+            vec![Type::Empty],
+            spawn_cobold,
+        )
+        .with_info(
+            "Spawn Kobold",
+            "Spawns a kobold with 10 health at the target.",
+            &["at"],
+            &["done"],
+        )
+        .with_cost(4)
+    }
+
+    pub fn empowered_punch() -> Self {
+        Self::new(
+            "empowered_punch",
+            vec![Type::Target, Type::Power],
+            vec![Type::Empty],
+            empowered_punch,
+        )
+        .with_info(
+            "Empowered Punch",
+            "Deals 10 damage to the target, plus 5 per power.",
+            &["target", "power"],
+            &["done"],
+        )
+        .with_cost(2)
+    }
+
+    pub fn fireball() -> Self {
+        Self::new(
+            "fireball",
+            vec![Type::Target, Type::Power],
+            vec![Type::Empty],
+            fireball,
+        )
+        .with_info(
+            "Fireball",
+            "Deals 4 damage, plus 2 per power, to every unit near the target. Power widens the blast.",
+            &["center", "power"],
+            &["done"],
+        )
+        .with_cost(4)
+    }
+
+    pub fn heal() -> Self {
+        Self::new(
+            "heal",
+            vec![Type::Target, Type::Power],
+            vec![Type::Target],
+            heal,
+        )
+        .with_info(
+            "Heal",
+            "Heals the target 2 health, plus 1 per power, for 2 ticks, plus 1 per power.",
+            &["target", "power"],
+            &["target"],
+        )
+        .with_cost(2)
+    }
+}
+
+// All spells known to the game, keyed by name. Built-in spells can be overridden by definitions.
+pub struct SpellLibrary(HashMap<String, Spell>);
+
+impl SpellLibrary {
+    pub fn with_builtins() -> Self {
+        let mut library = Self(HashMap::new());
+        for spell in [
+            Spell::player(),
+            Spell::punch(),
+            Spell::introspection(),
+            Spell::air(),
+            Spell::constrict(),
+            Spell::draw_life(),
+            Spell::scout(),
+            Spell::spawn_cobold(),
+            Spell::empowered_punch(),
+            Spell::fireball(),
+            Spell::heal(),
+            Spell::if_health_below(),
+            Spell::if_kobold(),
+            Spell::choose_nearest(),
+            Spell::repeat(),
+            Spell::for_each(),
+            Spell::each_unit(),
+            Spell::for_all(),
+            Spell::nearby(),
+            Spell::all_kobolds(),
+            Spell::area(),
+            Spell::flurry(),
+            Spell::meteor(),
+            Spell::count(),
+        ] {
+            library.insert(spell);
+        }
+        library
+    }
+
+    pub fn insert(&mut self, spell: Spell) {
+        self.0.insert(spell.name.clone(), spell);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Spell> {
+        self.0.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self
+            .0
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort_unstable();
+        names
     }
 }
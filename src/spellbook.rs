@@ -0,0 +1,167 @@
+use crate::{
+    spell::SpellLibrary,
+    spellbuilder::{CircuitBuilder, ConnectionError, SpellInput},
+    spellcircuit::Output,
+    spellscript::{self, ScriptError},
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+
+// Saved circuits are stored as `<slot>.circuit.ron` files in this directory, together with a
+// `<slot>.spell` copy written in the spell script language that can be edited by hand
+pub const SPELLBOOK_DIR: &str = "spellbook";
+
+// Bumped whenever the saved format changes in a way old files cannot be read
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedNode {
+    pub spell: String,
+    pub inputs: Vec<Option<Output>>,
+    pub position: Vec2,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedCircuit {
+    pub version: u32,
+    pub nodes: Vec<SavedNode>,
+    pub output: Option<Output>,
+}
+
+#[derive(Debug)]
+pub enum SpellbookError {
+    Io(std::io::Error),
+    Format(ron::Error),
+    UnsupportedVersion(u32),
+    UnknownSpell(String),
+    Connection(ConnectionError),
+    Script(ScriptError),
+}
+
+impl std::fmt::Display for SpellbookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SpellbookError::Io(err) => write!(f, "Could not access spellbook: {}", err),
+            SpellbookError::Format(err) => write!(f, "Saved circuit is malformed: {}", err),
+            SpellbookError::UnsupportedVersion(version) => {
+                write!(f, "Saved circuit has unsupported version {}", version)
+            }
+            SpellbookError::UnknownSpell(name) => write!(f, "Unknown spell {}", name),
+            SpellbookError::Connection(err) => write!(f, "{}", err),
+            SpellbookError::Script(err) => write!(f, "Spell script is invalid at {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for SpellbookError {
+    fn from(err: std::io::Error) -> Self {
+        SpellbookError::Io(err)
+    }
+}
+
+impl From<ron::Error> for SpellbookError {
+    fn from(err: ron::Error) -> Self {
+        SpellbookError::Format(err)
+    }
+}
+
+impl From<ConnectionError> for SpellbookError {
+    fn from(err: ConnectionError) -> Self {
+        SpellbookError::Connection(err)
+    }
+}
+
+impl From<ScriptError> for SpellbookError {
+    fn from(err: ScriptError) -> Self {
+        SpellbookError::Script(err)
+    }
+}
+
+impl SavedCircuit {
+    pub fn from_builder(builder: &CircuitBuilder, positions: &[Vec2]) -> Self {
+        let nodes = builder
+            .nodes
+            .iter()
+            .zip(positions.iter())
+            .map(|(node, position)| SavedNode {
+                spell: node.spell.name.clone(),
+                inputs: node.inputs.clone(),
+                position: *position,
+            })
+            .collect();
+        Self {
+            version: FORMAT_VERSION,
+            nodes,
+            output: builder.output.clone(),
+        }
+    }
+
+    // Rebuilds the circuit, checking every saved connection against the current spells
+    pub fn to_builder(&self, library: &SpellLibrary) -> Result<CircuitBuilder, SpellbookError> {
+        let spells = self
+            .nodes
+            .iter()
+            .map(|node| {
+                library
+                    .get(&node.spell)
+                    .cloned()
+                    .ok_or_else(|| SpellbookError::UnknownSpell(node.spell.clone()))
+            })
+            .collect::<Result<Vec<_>, SpellbookError>>()?;
+        let mut builder = CircuitBuilder::from_spells(spells);
+        for (i, node) in self.nodes.iter().enumerate() {
+            for (j, input) in node.inputs.iter().enumerate() {
+                if let Some(output) = input {
+                    builder.connect_io(SpellInput::new(i, j), output.clone())?;
+                }
+            }
+        }
+        if let Some(output) = &self.output {
+            builder.connect_io(SpellInput::CircuitOutput, output.clone())?;
+        }
+        Ok(builder)
+    }
+
+    pub fn positions(&self) -> Vec<Vec2> {
+        self.nodes.iter().map(|node| node.position).collect()
+    }
+}
+
+pub fn slot_path(slot: &str) -> PathBuf {
+    PathBuf::from(SPELLBOOK_DIR).join(format!("{}.circuit.ron", slot))
+}
+
+pub fn save(slot: &str, circuit: &SavedCircuit) -> Result<(), SpellbookError> {
+    let text = ron::ser::to_string_pretty(circuit, ron::ser::PrettyConfig::default())?;
+    fs::create_dir_all(SPELLBOOK_DIR)?;
+    fs::write(slot_path(slot), text)?;
+    Ok(())
+}
+
+pub fn load(slot: &str) -> Result<SavedCircuit, SpellbookError> {
+    let text = fs::read_to_string(slot_path(slot))?;
+    let circuit: SavedCircuit = ron::de::from_str(&text)?;
+    if circuit.version != FORMAT_VERSION {
+        return Err(SpellbookError::UnsupportedVersion(circuit.version));
+    }
+    Ok(circuit)
+}
+
+pub fn script_path(slot: &str) -> PathBuf {
+    PathBuf::from(SPELLBOOK_DIR).join(format!("{}.spell", slot))
+}
+
+pub fn save_script(slot: &str, builder: &CircuitBuilder) -> Result<(), SpellbookError> {
+    fs::create_dir_all(SPELLBOOK_DIR)?;
+    fs::write(
+        script_path(slot),
+        spellscript::print_builder(builder) + "\n",
+    )?;
+    Ok(())
+}
+
+pub fn load_script(slot: &str, library: &SpellLibrary) -> Result<CircuitBuilder, SpellbookError> {
+    let text = fs::read_to_string(script_path(slot))?;
+    Ok(spellscript::parse(&text, library)?)
+}
@@ -1,14 +1,28 @@
 use crate::{
+    encounter::Encounter,
+    history::{Edit, EditHistory, NodeChange, Rewire},
+    mouseclick::MouseClick,
     resources::DefaultFont,
-    spell::Spell,
-    spellcircuit::{Active, CircuitNode, Output, SpellCircuit},
+    spell::{Spell, SpellLibrary, Type, Value},
+    spellbook::{self, SavedCircuit},
+    spellcircuit::{
+        circuit_cost, Active, CircuitNode, Composite, Output, SpellCircuit, SpellDebugger,
+        SpellFizzled,
+    },
+    spelldefinition::DefinitionsLoaded,
+    state::GameState,
+    types::Mana,
+    unit::{Dying, Player},
 };
 use bevy::prelude::*;
 use bevy::ui::FocusPolicy;
 use rand::prelude::*;
+use std::collections::HashMap;
 
 const DEFAULT_OUTPUT_COLOR: Color = Color::BLACK;
 const SELECTED_OUTPUT_COLOR: Color = Color::SILVER;
+const CARD_COLOR: Color = Color::rgb(0.6, 0.7, 0.2);
+const ACTIVE_CARD_COLOR: Color = Color::rgb(0.9, 0.8, 0.3);
 
 struct SpellCard {
     position: Vec2,
@@ -17,7 +31,7 @@ struct SpellCard {
     node: usize,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum SpellInput {
     Spell(Output),
     CircuitOutput,
@@ -42,6 +56,7 @@ impl SpellCard {
         font: Handle<Font>,
         root: Entity,
         builder: &CircuitBuilder,
+        colors: &HashMap<Output, Color>,
     ) {
         let Self {
             position,
@@ -52,6 +67,7 @@ impl SpellCard {
         let child = commands
             .spawn_bundle(ButtonBundle {
                 style: Style {
+                    position_type: PositionType::Absolute,
                     position: Rect {
                         left: Val::Px(position.x),
                         bottom: Val::Px(position.y),
@@ -62,10 +78,10 @@ impl SpellCard {
                     justify_content: JustifyContent::SpaceBetween,
                     ..Default::default()
                 },
-                color: Color::rgb(0.6, 0.7, 0.2).into(),
+                color: CARD_COLOR.into(),
                 ..Default::default()
             })
-            .insert(SpellCardTag)
+            .insert(SpellCardTag(node))
             .with_children(|parent| {
                 // Input buttons
                 parent
@@ -84,17 +100,32 @@ impl SpellCard {
                     })
                     .insert(FocusPolicy::Pass)
                     .with_children(|parent| {
-                        for input in 0..builder.nodes[node].spell.num_inputs {
+                        for (input, output) in builder.nodes[node].inputs.iter().enumerate() {
+                            let color = output
+                                .as_ref()
+                                .and_then(|output| colors.get(output))
+                                .unwrap_or(&DEFAULT_OUTPUT_COLOR);
                             parent
                                 .spawn_bundle(ButtonBundle {
                                     style: Style {
                                         size: Size::new(Val::Px(20.), Val::Px(20.)),
                                         ..Default::default()
                                     },
-                                    color: DEFAULT_OUTPUT_COLOR.into(),
+                                    color: (*color).into(),
                                     ..Default::default()
                                 })
-                                .insert(CardInput(SpellInput::new(node, input)));
+                                .insert(CardInput(SpellInput::new(node, input)))
+                                .with_children(|parent| {
+                                    let label = &builder.nodes[node].spell.info.input_labels;
+                                    parent.spawn_bundle(port_label(
+                                        &label[input],
+                                        font.clone(),
+                                        Rect {
+                                            left: Val::Px(22.),
+                                            ..Default::default()
+                                        },
+                                    ));
+                                });
                         }
                     });
                 // Text
@@ -123,13 +154,17 @@ impl SpellCard {
                             ),
                             ..Default::default()
                         });
-                        // Description
+                        // Description, wrapped to the width of the column
                         parent.spawn_bundle(TextBundle {
+                            style: Style {
+                                max_size: Size::new(Val::Px(96.), Val::Undefined),
+                                ..Default::default()
+                            },
                             text: Text::with_section(
                                 description,
                                 TextStyle {
                                     font: font.clone(),
-                                    font_size: 16.,
+                                    font_size: 14.,
                                     color: Color::rgb(0., 0., 0.),
                                 },
                                 Default::default(),
@@ -153,17 +188,53 @@ impl SpellCard {
                     })
                     .insert(FocusPolicy::Pass)
                     .with_children(|parent| {
-                        for output in 0..builder.nodes[node].spell.num_outputs {
+                        for output in 0..builder.nodes[node].spell.num_outputs() {
+                            let color = colors
+                                .get(&Output::new(node, output))
+                                .unwrap_or(&DEFAULT_OUTPUT_COLOR);
                             parent
                                 .spawn_bundle(ButtonBundle {
                                     style: Style {
                                         size: Size::new(Val::Px(20.), Val::Px(20.)),
                                         ..Default::default()
                                     },
-                                    color: DEFAULT_OUTPUT_COLOR.into(),
+                                    color: (*color).into(),
                                     ..Default::default()
                                 })
-                                .insert(CardOutput(Output::new(node, output)));
+                                .insert(CardOutput(Output::new(node, output)))
+                                .with_children(|parent| {
+                                    let label = &builder.nodes[node].spell.info.output_labels;
+                                    parent.spawn_bundle(port_label(
+                                        &label[output],
+                                        font.clone(),
+                                        Rect {
+                                            right: Val::Px(22.),
+                                            ..Default::default()
+                                        },
+                                    ));
+                                    parent
+                                        .spawn_bundle(TextBundle {
+                                            style: Style {
+                                                position_type: PositionType::Absolute,
+                                                position: Rect {
+                                                    left: Val::Px(22.),
+                                                    ..Default::default()
+                                                },
+                                                ..Default::default()
+                                            },
+                                            text: Text::with_section(
+                                                "",
+                                                TextStyle {
+                                                    font: font.clone(),
+                                                    font_size: 14.,
+                                                    color: Color::WHITE,
+                                                },
+                                                Default::default(),
+                                            ),
+                                            ..Default::default()
+                                        })
+                                        .insert(OutputValueText(Output::new(node, output)));
+                                });
                         }
                     });
             })
@@ -172,31 +243,83 @@ impl SpellCard {
     }
 }
 
+// Text next to a port of a card, telling what the port is for
+fn port_label(label: &str, font: Handle<Font>, position: Rect<Val>) -> TextBundle {
+    TextBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position,
+            ..Default::default()
+        },
+        text: Text::with_section(
+            label,
+            TextStyle {
+                font,
+                font_size: 12.,
+                color: Color::BLACK,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    }
+}
+
 #[derive(Component)]
 struct Selected(Vec2);
 
 #[derive(Component)]
-struct SpellCardTag;
+struct SpellCardTag(usize);
+
+// Where a card was when it started being dragged
+#[derive(Component)]
+struct DragStart(Vec2);
+
+fn card_position(style: &Style) -> Option<Vec2> {
+    match (style.position.left, style.position.bottom) {
+        (Val::Px(x), Val::Px(y)) => Some(Vec2::new(x, y)),
+        _ => None,
+    }
+}
 
 fn select_card_system(
     mut commands: Commands,
     windows: Res<Windows>,
+    mut history: ResMut<EditHistory>,
     interaction_query: Query<
-        (Entity, &Style, &Interaction),
+        (
+            Entity,
+            &SpellCardTag,
+            &Style,
+            &Interaction,
+            Option<&DragStart>,
+        ),
         (Changed<Interaction>, With<SpellCardTag>),
     >,
 ) {
     let window = windows.get_primary().unwrap();
     if let Some(position) = window.cursor_position() {
-        for (card, style, interaction) in interaction_query.iter() {
+        for (card, SpellCardTag(node), style, interaction, start) in interaction_query.iter() {
             if let Interaction::Clicked = interaction {
-                if let (Val::Px(x), Val::Px(y)) = (style.position.left, style.position.bottom) {
+                if let Some(card_pos) = card_position(style) {
                     commands
                         .entity(card)
-                        .insert(Selected(Vec2::new(x - position.x, y - position.y)));
+                        .insert(Selected(card_pos - position))
+                        .insert(DragStart(card_pos));
                 }
             } else {
-                commands.entity(card).remove::<Selected>();
+                commands
+                    .entity(card)
+                    .remove::<Selected>()
+                    .remove::<DragStart>();
+                if let (Some(DragStart(from)), Some(to)) = (start, card_position(style)) {
+                    if *from != to {
+                        history.push(Edit::MoveCard {
+                            node: *node,
+                            from: *from,
+                            to,
+                        });
+                    }
+                }
             }
         }
     }
@@ -222,14 +345,12 @@ fn select_output(
     >,
     mut q_other: Query<(Entity, &mut UiColor), (With<CardOutput>, With<Selected>)>,
 ) {
-    if let Ok((id, interaction, mut color)) = q_output.get_single_mut() {
-        if let Interaction::Clicked = interaction {
-            color.0 = SELECTED_OUTPUT_COLOR;
-            commands.entity(id).insert(Selected(Vec2::ZERO));
-            for (id, mut color) in q_other.iter_mut() {
-                color.0 = DEFAULT_OUTPUT_COLOR;
-                commands.entity(id).remove::<Selected>();
-            }
+    if let Ok((id, Interaction::Clicked, mut color)) = q_output.get_single_mut() {
+        color.0 = SELECTED_OUTPUT_COLOR;
+        commands.entity(id).insert(Selected(Vec2::ZERO));
+        for (id, mut color) in q_other.iter_mut() {
+            color.0 = DEFAULT_OUTPUT_COLOR;
+            commands.entity(id).remove::<Selected>();
         }
     }
 }
@@ -242,17 +363,36 @@ fn connect_to_input(
         (With<CardInput>, Changed<Interaction>),
     >,
     mut q_output: Query<(Entity, &CardOutput, &mut UiColor), (With<Selected>, Without<CardInput>)>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+    mut colors: ResMut<PortColors>,
     mut rng: ResMut<StdRng>,
+    mut history: ResMut<EditHistory>,
 ) {
-    if let Ok((input, interaction, mut i_color)) = q_input.get_single_mut() {
-        if let Interaction::Clicked = interaction {
-            if let Ok((output_id, output, mut o_color)) = q_output.get_single_mut() {
-                builder.connect_io(input.0.clone(), output.0.clone());
-                let color = Color::hsl(rng.gen::<f32>() * 360., 1., 0.5);
-                i_color.0 = color;
-                o_color.0 = color;
-                commands.entity(output_id).remove::<Selected>();
+    if let Ok((input, Interaction::Clicked, mut i_color)) = q_input.get_single_mut() {
+        if let Ok((output_id, output, mut o_color)) = q_output.get_single_mut() {
+            let message = &mut q_message.single_mut().sections[0].value;
+            let before = builder.connected_output(&input.0);
+            match builder.connect_io(input.0.clone(), output.0.clone()) {
+                Ok(()) => {
+                    history.push(Edit::Rewire(vec![Rewire {
+                        input: input.0.clone(),
+                        before,
+                        after: Some(output.0.clone()),
+                    }]));
+                    let color = *colors
+                        .0
+                        .entry(output.0.clone())
+                        .or_insert_with(|| random_color(&mut rng));
+                    i_color.0 = color;
+                    o_color.0 = color;
+                    message.clear();
+                }
+                Err(err) => {
+                    o_color.0 = DEFAULT_OUTPUT_COLOR;
+                    *message = err.to_string();
+                }
             }
+            commands.entity(output_id).remove::<Selected>();
         }
     }
 }
@@ -260,36 +400,589 @@ fn connect_to_input(
 fn compile_circuit(
     mut commands: Commands,
     keys: Res<Input<KeyCode>>,
-    builder: ResMut<CircuitBuilder>,
-    q_active: Query<&Active>,
+    builder: Res<CircuitBuilder>,
+    mut q_mana: Query<&mut Mana, (With<Player>, Without<Dying>)>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+) {
+    if keys.just_pressed(KeyCode::Return) {
+        let message = &mut q_message.single_mut().sections[0].value;
+        let mut mana = match q_mana.get_single_mut() {
+            Ok(mana) => mana,
+            Err(_) => {
+                *message = "The player is dead and cannot cast spells.".to_string();
+                return;
+            }
+        };
+        match builder.compile() {
+            Ok(circuit) => {
+                if mana.spend(circuit.cost()) {
+                    commands.spawn_bundle((circuit, Active, FromBuilder));
+                    message.clear();
+                } else {
+                    *message = format!(
+                        "Not enough mana: the circuit costs {} but you have {}.",
+                        circuit.cost(),
+                        mana.current.floor()
+                    );
+                }
+            }
+            Err(err) => *message = err.to_string(),
+        }
+    }
+}
+
+// Shows the mana cost of the circuit in the builder next to the mana of the player
+#[derive(Component)]
+struct CostText;
+
+fn cost_text_system(
+    builder: Res<CircuitBuilder>,
+    q_mana: Query<&Mana, With<Player>>,
+    mut q_text: Query<&mut Text, With<CostText>>,
+) {
+    let mana = q_mana.get_single().map_or(0., |mana| mana.current.floor());
+    let label = format!("Cost: {}  Mana: {}", builder.cost(), mana);
+    let text = &mut q_text.single_mut().sections[0].value;
+    if *text != label {
+        *text = label;
+    }
+}
+
+// Marks circuits compiled from the builder, whose nodes correspond to the cards
+#[derive(Component)]
+struct FromBuilder;
+
+// Shows the value computed for an output while debugging
+#[derive(Component)]
+struct OutputValueText(Output);
+
+fn value_label(value: &Value) -> String {
+    match value {
+        Value::Target(entity) => format!("T{}", entity.id()),
+        Value::Power(power) => power.to_string(),
+        Value::Empty => "-".to_string(),
+        Value::Targets(targets) => format!("{} units", targets.len()),
+        Value::Area { radius, .. } => format!("r{}", radius.round()),
+    }
+}
+
+// Highlights the card that ran last and shows computed outputs while the debugger is enabled
+fn debug_overlay_system(
+    debugger: Res<SpellDebugger>,
+    q_circuit: Query<&SpellCircuit, (With<Active>, With<FromBuilder>)>,
+    mut q_cards: Query<(&SpellCardTag, &mut UiColor)>,
+    mut q_values: Query<(&OutputValueText, &mut Text)>,
+) {
+    let circuit = q_circuit.get_single().ok().filter(|_| debugger.enabled);
+    let active = circuit.and_then(|circuit| circuit.last_executed());
+    for (SpellCardTag(node), mut color) in q_cards.iter_mut() {
+        color.0 = if active == Some(*node) {
+            ACTIVE_CARD_COLOR
+        } else {
+            CARD_COLOR
+        };
+    }
+    for (OutputValueText(output), mut text) in q_values.iter_mut() {
+        let value = circuit
+            .and_then(|circuit| circuit.nodes.get(output.node))
+            .and_then(|node| node.outputs.as_ref())
+            .and_then(|outputs| outputs.get(output.index));
+        text.sections[0].value = value.map(value_label).unwrap_or_default();
+    }
+}
+
+const WIRE_WIDTH: f32 = 3.;
+
+// A line drawn between a connected input and output. The ends are updated as cards move.
+#[derive(Component)]
+struct Wire {
+    input: SpellInput,
+    output: Output,
+    start: Vec2,
+    end: Vec2,
+}
+
+// Respawns the wires whenever the connections in the builder change
+fn spawn_wires_system(
+    mut commands: Commands,
+    builder: Res<CircuitBuilder>,
+    q_root: Query<Entity, With<SpellBuilderUI>>,
+    q_wires: Query<Entity, With<Wire>>,
+) {
+    if !builder.is_changed() {
+        return;
+    }
+    for wire in q_wires.iter() {
+        commands.entity(wire).despawn_recursive();
+    }
+    let root = q_root.single();
+    for (input, output) in builder.connections() {
+        let wire = commands
+            .spawn_bundle(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    size: Size::new(Val::Px(0.), Val::Px(WIRE_WIDTH)),
+                    ..Default::default()
+                },
+                color: Color::NONE.into(),
+                ..Default::default()
+            })
+            .insert(FocusPolicy::Pass)
+            .insert(Wire {
+                input,
+                output,
+                start: Vec2::ZERO,
+                end: Vec2::ZERO,
+            })
+            .id();
+        commands.entity(root).push_children(&[wire]);
+    }
+}
+
+// Stretches and rotates each wire to run between its ports, in the color of its input
+fn update_wires_system(
+    q_inputs: Query<(&CardInput, &GlobalTransform, &UiColor)>,
+    q_outputs: Query<(&CardOutput, &GlobalTransform)>,
+    mut q_wires: Query<(&mut Wire, &mut Style, &mut Transform, &mut UiColor), Without<CardInput>>,
 ) {
-    if keys.just_pressed(KeyCode::Return) && q_active.is_empty() {
-        if let Some(circuit) = builder.compile() {
-            commands.spawn_bundle((circuit, Active));
+    for (mut wire, mut style, mut transform, mut color) in q_wires.iter_mut() {
+        let input = q_inputs.iter().find(|(input, _, _)| input.0 == wire.input);
+        let output = q_outputs.iter().find(|(output, _)| output.0 == wire.output);
+        if let (Some((_, start, input_color)), Some((_, end))) = (input, output) {
+            let (start, end) = (start.translation.truncate(), end.translation.truncate());
+            if wire.start == start && wire.end == end && color.0 == input_color.0 {
+                continue;
+            }
+            let diff = end - start;
+            let center = (start + end) / 2.;
+            style.size.width = Val::Px(diff.length());
+            style.position.left = Val::Px(center.x - diff.length() / 2.);
+            style.position.bottom = Val::Px(center.y - WIRE_WIDTH / 2.);
+            transform.rotation = Quat::from_rotation_z(diff.y.atan2(diff.x));
+            color.0 = input_color.0;
+            wire.start = start;
+            wire.end = end;
+        }
+    }
+}
+
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let diff = end - start;
+    let t = if diff.length_squared() > 0. {
+        ((point - start).dot(diff) / diff.length_squared()).clamp(0., 1.)
+    } else {
+        0.
+    };
+    point.distance(start + t * diff)
+}
+
+// Clicking a wire away from its ports disconnects it. Clicks are sent when the button is
+// released, so clicks that were pressed on a card or port, like dragging a card, are ignored.
+fn click_wire_system(
+    mouse: Res<Input<MouseButton>>,
+    mut ev_mouseclick: EventReader<MouseClick>,
+    mut builder: ResMut<CircuitBuilder>,
+    q_wires: Query<&Wire>,
+    q_interactions: Query<
+        &Interaction,
+        Or<(With<SpellCardTag>, With<CardInput>, With<CardOutput>)>,
+    >,
+    mut pressed_on_card: Local<bool>,
+    mut history: ResMut<EditHistory>,
+) {
+    if mouse.just_pressed(MouseButton::Left) {
+        *pressed_on_card = q_interactions
+            .iter()
+            .any(|interaction| *interaction == Interaction::Clicked);
+    }
+    for click in ev_mouseclick.iter() {
+        if *pressed_on_card {
+            continue;
+        }
+        let position = click.window_position;
+        let clicked = q_wires.iter().find(|wire| {
+            distance_to_segment(position, wire.start, wire.end) < WIRE_WIDTH + 2.
+                && position.distance(wire.start) > 15.
+                && position.distance(wire.end) > 15.
+        });
+        if let Some(wire) = clicked {
+            if let Some(output) = builder.disconnect_input(&wire.input) {
+                history.push(Edit::Rewire(vec![Rewire {
+                    input: wire.input.clone(),
+                    before: Some(output),
+                    after: None,
+                }]));
+            }
+        }
+    }
+}
+
+// Right clicking an input disconnects it, right clicking an output disconnects everything using it
+fn right_click_port_system(
+    mouse: Res<Input<MouseButton>>,
+    mut builder: ResMut<CircuitBuilder>,
+    q_inputs: Query<(&CardInput, &Interaction)>,
+    q_outputs: Query<(&CardOutput, &Interaction)>,
+    mut history: ResMut<EditHistory>,
+) {
+    if !mouse.just_pressed(MouseButton::Right) {
+        return;
+    }
+    let mut rewires = vec![];
+    for (input, interaction) in q_inputs.iter() {
+        if let Interaction::Hovered = interaction {
+            if let Some(output) = builder.disconnect_input(&input.0) {
+                rewires.push(Rewire {
+                    input: input.0.clone(),
+                    before: Some(output),
+                    after: None,
+                });
+            }
         }
     }
+    for (output, interaction) in q_outputs.iter() {
+        if let Interaction::Hovered = interaction {
+            for input in builder.disconnect_output(&output.0) {
+                rewires.push(Rewire {
+                    input,
+                    before: Some(output.0.clone()),
+                    after: None,
+                });
+            }
+        }
+    }
+    if !rewires.is_empty() {
+        history.push(Edit::Rewire(rewires));
+    }
+}
+
+// Ctrl + Z undoes the latest edit in the builder, Ctrl + Shift + Z redoes it
+fn undo_system(
+    keys: Res<Input<KeyCode>>,
+    mut history: ResMut<EditHistory>,
+    mut ev_edit: EventWriter<BuilderEdit>,
+) {
+    if !keys.just_pressed(KeyCode::Z) || !keys.any_pressed([KeyCode::LControl, KeyCode::RControl]) {
+        return;
+    }
+    let edit = if keys.any_pressed([KeyCode::LShift, KeyCode::RShift]) {
+        history.redo()
+    } else {
+        history.undo()
+    };
+    if let Some(edit) = edit {
+        ev_edit.send(BuilderEdit(edit));
+    }
+}
+
+// An edit to apply to the builder and its cards, for edits that need more than the builder
+struct BuilderEdit(Edit);
+
+// The positions of the cards, indexed by node
+fn card_positions<'a>(
+    cards: impl Iterator<Item = (&'a SpellCardTag, &'a Style)>,
+    len: usize,
+) -> Vec<Vec2> {
+    let mut positions = vec![Vec2::ZERO; len];
+    for (SpellCardTag(node), style) in cards {
+        if let Some(position) = card_position(style) {
+            positions[*node] = position;
+        }
+    }
+    positions
+}
+
+// Applies edits to the builder. Cards are respawned when nodes were added or removed, since the
+// ports of every later card change index.
+fn apply_edits_system(
+    mut commands: Commands,
+    mut ev_edit: EventReader<BuilderEdit>,
+    font: Res<DefaultFont>,
+    mut builder: ResMut<CircuitBuilder>,
+    mut colors: ResMut<PortColors>,
+    mut rng: ResMut<StdRng>,
+    q_root: Query<Entity, With<SpellBuilderUI>>,
+    mut q_cards: Query<(Entity, &SpellCardTag, &mut Style), Without<SpellBuilderUI>>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+) {
+    let edits: Vec<&BuilderEdit> = ev_edit.iter().collect();
+    if edits.is_empty() {
+        return;
+    }
+    let mut positions = card_positions(
+        q_cards.iter().map(|(_, tag, style)| (tag, style)),
+        builder.nodes.len(),
+    );
+    let mut respawn = false;
+    for BuilderEdit(edit) in edits {
+        respawn |= edit.changes_nodes();
+        if let Err(err) = edit.apply(&mut builder, &mut positions) {
+            q_message.single_mut().sections[0].value = err.to_string();
+        }
+    }
+    if respawn {
+        for (card, _, _) in q_cards.iter() {
+            commands.entity(card).despawn_recursive();
+        }
+        assign_colors(&builder, &mut colors.0, &mut rng);
+        spawn_cards(
+            &mut commands,
+            font.0.clone(),
+            q_root.single(),
+            &builder,
+            &positions,
+            &colors.0,
+        );
+    } else {
+        for (_, SpellCardTag(node), mut style) in q_cards.iter_mut() {
+            if card_position(&style) != Some(positions[*node]) {
+                style.position.left = Val::Px(positions[*node].x);
+                style.position.bottom = Val::Px(positions[*node].y);
+            }
+        }
+    }
+}
+
+// Lists every spell in the library. Clicking an entry adds a card casting that spell.
+#[derive(Component)]
+struct Palette;
+
+#[derive(Component)]
+struct PaletteEntry(String);
+
+// Rebuilds the palette whenever spells are added to the library
+fn palette_system(
+    mut commands: Commands,
+    font: Res<DefaultFont>,
+    library: Res<SpellLibrary>,
+    q_palette: Query<Entity, With<Palette>>,
+    q_entries: Query<Entity, With<PaletteEntry>>,
+) {
+    if !library.is_changed() {
+        return;
+    }
+    for entry in q_entries.iter() {
+        commands.entity(entry).despawn_recursive();
+    }
+    let palette = q_palette.single();
+    for name in library.names() {
+        let spell = library.get(name).unwrap();
+        let label = format!("{} ({})", spell.info.display_name, spell.cost);
+        let entry = commands
+            .spawn_bundle(ButtonBundle {
+                style: Style {
+                    size: Size::new(Val::Px(140.), Val::Px(24.)),
+                    margin: Rect::all(Val::Px(2.)),
+                    padding: Rect {
+                        left: Val::Px(4.),
+                        ..Default::default()
+                    },
+                    align_items: AlignItems::Center,
+                    ..Default::default()
+                },
+                color: CARD_COLOR.into(),
+                ..Default::default()
+            })
+            .insert(PaletteEntry(name.to_string()))
+            .with_children(|parent| {
+                parent.spawn_bundle(TextBundle {
+                    text: Text::with_section(
+                        label,
+                        TextStyle {
+                            font: font.0.clone(),
+                            font_size: 16.,
+                            color: Color::BLACK,
+                        },
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                });
+            })
+            .id();
+        commands.entity(palette).push_children(&[entry]);
+    }
+}
+
+fn palette_click_system(
+    library: Res<SpellLibrary>,
+    builder: Res<CircuitBuilder>,
+    mut history: ResMut<EditHistory>,
+    q_entries: Query<(&PaletteEntry, &Interaction), Changed<Interaction>>,
+    mut ev_edit: EventWriter<BuilderEdit>,
+) {
+    for (PaletteEntry(name), interaction) in q_entries.iter() {
+        if let (Interaction::Clicked, Some(spell)) = (interaction, library.get(name)) {
+            let index = builder.nodes.len();
+            let edit = Edit::InsertNode(NodeChange {
+                index,
+                node: BuilderNode {
+                    inputs: vec![None; spell.num_inputs()],
+                    spell: spell.clone(),
+                },
+                position: Vec2::new(80. + 210. * (index % 5) as f32, 270.),
+                rewires: vec![],
+            });
+            history.push(edit.clone());
+            ev_edit.send(BuilderEdit(edit));
+        }
+    }
+}
+
+// Pressing Delete while hovering a card removes its node from the builder
+fn delete_card_system(
+    keys: Res<Input<KeyCode>>,
+    builder: Res<CircuitBuilder>,
+    mut history: ResMut<EditHistory>,
+    q_cards: Query<(&SpellCardTag, &Style, &Interaction)>,
+    mut ev_edit: EventWriter<BuilderEdit>,
+) {
+    if !keys.just_pressed(KeyCode::Delete) {
+        return;
+    }
+    for (SpellCardTag(index), style, interaction) in q_cards.iter() {
+        if let Interaction::Hovered = interaction {
+            let rewires = builder
+                .connections()
+                .into_iter()
+                .filter(|(_, output)| output.node == *index)
+                .map(|(input, output)| Rewire {
+                    input,
+                    before: Some(output),
+                    after: None,
+                })
+                .collect();
+            let edit = Edit::RemoveNode(NodeChange {
+                index: *index,
+                node: builder.nodes[*index].clone(),
+                position: card_position(style).unwrap_or_default(),
+                rewires,
+            });
+            history.push(edit.clone());
+            ev_edit.send(BuilderEdit(edit));
+        }
+    }
+}
+
+// P packages the builder as a new spell in the palette. Its unconnected inputs become the
+// inputs of the spell and the circuit output becomes its output.
+fn package_system(
+    keys: Res<Input<KeyCode>>,
+    builder: Res<CircuitBuilder>,
+    mut library: ResMut<SpellLibrary>,
+    mut packaged: Local<usize>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+) {
+    if !keys.just_pressed(KeyCode::P) {
+        return;
+    }
+    let name = format!("custom_{}", *packaged + 1);
+    let outputs = builder.output.iter().cloned().collect();
+    let message = &mut q_message.single_mut().sections[0].value;
+    match builder.package(&name, builder.unconnected_inputs(), outputs) {
+        Ok(mut spell) => {
+            spell.info.display_name = format!("Custom {}", *packaged + 1);
+            *message = format!("Packaged the circuit as {}.", spell.info.display_name);
+            library.insert(spell);
+            *packaged += 1;
+        }
+        Err(err) => *message = err.to_string(),
+    }
+}
+
+// Colors of the outputs that are connected to something. Every input shares the color of its output.
+#[derive(Default)]
+pub struct PortColors(pub HashMap<Output, Color>);
+
+fn random_color(rng: &mut StdRng) -> Color {
+    Color::hsl(rng.gen::<f32>() * 360., 1., 0.5)
+}
+
+// Forgets the colors of outputs that are no longer connected and picks colors for new connections
+fn assign_colors(builder: &CircuitBuilder, colors: &mut HashMap<Output, Color>, rng: &mut StdRng) {
+    let connections = builder.connections();
+    colors.retain(|output, _| connections.iter().any(|(_, o)| o == output));
+    for (_, output) in connections {
+        colors.entry(output).or_insert_with(|| random_color(rng));
+    }
+}
+
+// Keeps the port colors in sync with the builder, so orphaned ports return to the default color
+fn recolor_ports_system(
+    builder: Res<CircuitBuilder>,
+    mut colors: ResMut<PortColors>,
+    mut rng: ResMut<StdRng>,
+    mut q_inputs: Query<(&CardInput, &mut UiColor)>,
+    mut q_outputs: Query<(&CardOutput, &mut UiColor), (Without<Selected>, Without<CardInput>)>,
+) {
+    if !builder.is_changed() {
+        return;
+    }
+    assign_colors(&builder, &mut colors.0, &mut rng);
+    let connections: HashMap<SpellInput, Output> = builder.connections().into_iter().collect();
+    for (input, mut color) in q_inputs.iter_mut() {
+        color.0 = *connections
+            .get(&input.0)
+            .and_then(|output| colors.0.get(output))
+            .unwrap_or(&DEFAULT_OUTPUT_COLOR);
+    }
+    for (output, mut color) in q_outputs.iter_mut() {
+        color.0 = *colors.0.get(&output.0).unwrap_or(&DEFAULT_OUTPUT_COLOR);
+    }
+}
+
+fn show_fizzle_system(
+    mut ev_fizzled: EventReader<SpellFizzled>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+) {
+    for SpellFizzled(reason) in ev_fizzled.iter() {
+        q_message.single_mut().sections[0].value = format!("Spell fizzled. {}.", reason);
+    }
 }
 
 pub struct SpellBuilderPlugin;
 
 impl Plugin for SpellBuilderPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup)
-            .add_system(select_card_system)
-            .add_system(move_card_system)
-            .add_system(select_output)
-            .add_system(connect_to_input)
-            .add_system(compile_circuit);
+        app.add_event::<BuilderEdit>()
+            .init_resource::<PortColors>()
+            .init_resource::<EditHistory>()
+            .add_startup_system(setup)
+            .add_system_set(
+                SystemSet::on_update(GameState::BuildSpell)
+                    .with_system(select_card_system)
+                    .with_system(move_card_system)
+                    .with_system(select_output)
+                    .with_system(connect_to_input)
+                    .with_system(compile_circuit)
+                    .with_system(spellbook_system)
+                    .with_system(click_wire_system)
+                    .with_system(right_click_port_system)
+                    .with_system(undo_system)
+                    .with_system(palette_click_system)
+                    .with_system(delete_card_system)
+                    .with_system(package_system),
+            )
+            .add_system(show_fizzle_system)
+            .add_system(debug_overlay_system)
+            .add_system(spawn_wires_system)
+            .add_system(update_wires_system)
+            .add_system(recolor_ports_system)
+            .add_system(apply_edits_system)
+            .add_system(palette_system)
+            .add_system(starting_spells_system)
+            .add_system(cost_text_system);
     }
 }
 
 #[derive(Component)]
 pub struct SpellBuilderUI;
 
+// Text shown in the builder explaining why the last action failed
+#[derive(Component)]
+struct BuilderMessage;
+
 fn setup(mut commands: Commands, font: Res<DefaultFont>) {
-    let builder =
-        CircuitBuilder::from_spells(vec![Spell::player(), Spell::punch(), Spell::constrict()]);
-    let root = commands
+    commands
         .spawn_bundle(NodeBundle {
             style: Style {
                 size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
@@ -316,26 +1009,308 @@ fn setup(mut commands: Commands, font: Res<DefaultFont>) {
                     ..Default::default()
                 })
                 .insert(CardInput(SpellInput::CircuitOutput));
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            left: Val::Px(10.),
+                            top: Val::Px(10.),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font: font.0.clone(),
+                            font_size: 20.,
+                            color: Color::rgb(0.9, 0.3, 0.3),
+                        },
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                })
+                .insert(BuilderMessage);
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            right: Val::Px(10.),
+                            top: Val::Px(10.),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font: font.0.clone(),
+                            font_size: 20.,
+                            color: Color::rgb(0.4, 0.6, 1.),
+                        },
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                })
+                .insert(CostText);
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            right: Val::Px(10.),
+                            top: Val::Px(40.),
+                            ..Default::default()
+                        },
+                        flex_direction: FlexDirection::ColumnReverse,
+                        ..Default::default()
+                    },
+                    color: Color::NONE.into(),
+                    ..Default::default()
+                })
+                .insert(Palette);
+        });
+    commands.insert_resource(CircuitBuilder::from_spells(vec![]));
+}
+
+// Puts the starting spells of the encounter in the builder once the spell definitions they may
+// refer to have loaded
+fn starting_spells_system(
+    mut commands: Commands,
+    font: Res<DefaultFont>,
+    loaded: Res<DefinitionsLoaded>,
+    library: Res<SpellLibrary>,
+    encounter: Res<Encounter>,
+    mut builder: ResMut<CircuitBuilder>,
+    q_root: Query<Entity, With<SpellBuilderUI>>,
+) {
+    if !loaded.is_changed() || !loaded.0 {
+        return;
+    }
+    let spells = encounter
+        .starting_spells
+        .iter()
+        .filter_map(|name| {
+            let spell = library.get(name).cloned();
+            if spell.is_none() {
+                warn!("Unknown starting spell {}.", name);
+            }
+            spell
         })
-        .id();
-    let cards = (0..builder.nodes.len()).map(|i| SpellCard {
-        position: Vec2::new(50.0 + 50.0 * i as f32, 50.0),
-        name: "Test".to_string(),
-        description: i.to_string(),
+        .collect();
+    *builder = CircuitBuilder::from_spells(spells);
+    let positions = (0..builder.nodes.len())
+        .map(|i| Vec2::new(80.0 + 210.0 * i as f32, 50.0))
+        .collect::<Vec<Vec2>>();
+    spawn_cards(
+        &mut commands,
+        font.0.clone(),
+        q_root.single(),
+        &builder,
+        &positions,
+        &HashMap::new(),
+    );
+}
+
+fn spawn_cards(
+    commands: &mut Commands,
+    font: Handle<Font>,
+    root: Entity,
+    builder: &CircuitBuilder,
+    positions: &[Vec2],
+    colors: &HashMap<Output, Color>,
+) {
+    let cards = positions.iter().enumerate().map(|(i, position)| SpellCard {
+        position: *position,
+        name: builder.nodes[i].spell.info.display_name.clone(),
+        description: builder.nodes[i].spell.info.description.clone(),
         node: i,
     });
-    cards.for_each(|card| card.spawn(&mut commands, font.0.clone(), root, &builder));
-    commands.insert_resource(builder);
+    cards.for_each(|card| card.spawn(commands, font.clone(), root, builder, colors));
+}
+
+const SLOT_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+
+// Ctrl + number saves the circuit to a spellbook slot, Shift + number loads it and Alt + number
+// loads the spell script of the slot instead
+fn spellbook_system(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    font: Res<DefaultFont>,
+    library: Res<SpellLibrary>,
+    mut builder: ResMut<CircuitBuilder>,
+    q_root: Query<Entity, With<SpellBuilderUI>>,
+    q_cards: Query<(Entity, &SpellCardTag, &Style)>,
+    mut q_message: Query<&mut Text, With<BuilderMessage>>,
+    mut colors: ResMut<PortColors>,
+    mut rng: ResMut<StdRng>,
+    mut history: ResMut<EditHistory>,
+) {
+    let root = q_root.single();
+    let slot = match SLOT_KEYS.iter().position(|key| keys.just_pressed(*key)) {
+        Some(i) => format!("slot{}", i + 1),
+        None => return,
+    };
+    let ctrl = keys.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+    let shift = keys.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    let alt = keys.any_pressed([KeyCode::LAlt, KeyCode::RAlt]);
+    let message = &mut q_message.single_mut().sections[0].value;
+    if ctrl {
+        let positions = card_positions(
+            q_cards.iter().map(|(_, tag, style)| (tag, style)),
+            builder.nodes.len(),
+        );
+        let saved = SavedCircuit::from_builder(&builder, &positions);
+        *message = match spellbook::save(&slot, &saved)
+            .and_then(|()| spellbook::save_script(&slot, &builder))
+        {
+            Ok(()) => format!("Saved circuit to {}.", slot),
+            Err(err) => err.to_string(),
+        };
+        return;
+    }
+    let loaded = if shift {
+        spellbook::load(&slot).and_then(|saved| {
+            let positions = saved.positions();
+            saved
+                .to_builder(&library)
+                .map(|builder| (builder, positions))
+        })
+    } else if alt {
+        // Scripts have no card positions, so the cards are laid out in rows
+        spellbook::load_script(&slot, &library).map(|builder| {
+            let positions = (0..builder.nodes.len())
+                .map(|i| Vec2::new(80. + 210. * (i % 5) as f32, 50. + 220. * (i / 5) as f32))
+                .collect();
+            (builder, positions)
+        })
+    } else {
+        return;
+    };
+    match loaded {
+        Ok((loaded, positions)) => {
+            for (card, _, _) in q_cards.iter() {
+                commands.entity(card).despawn_recursive();
+            }
+            colors.0.clear();
+            assign_colors(&loaded, &mut colors.0, &mut rng);
+            spawn_cards(
+                &mut commands,
+                font.0.clone(),
+                root,
+                &loaded,
+                &positions,
+                &colors.0,
+            );
+            *builder = loaded;
+            history.clear();
+            *message = format!("Loaded circuit from {}.", slot);
+        }
+        Err(err) => *message = err.to_string(),
+    }
 }
 
-struct BuilderNode {
-    inputs: Vec<Option<Output>>,
-    spell: Spell,
+// Reasons a connection between two ports can be rejected by the builder
+#[derive(Clone, Debug)]
+pub enum ConnectionError {
+    InvalidInput(Output),
+    InvalidOutput(Output),
+    TypeMismatch {
+        input: Output,
+        output: Output,
+        expected: Type,
+        found: Type,
+    },
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectionError::InvalidInput(input) => {
+                write!(f, "Node {} has no input {}.", input.node, input.index)
+            }
+            ConnectionError::InvalidOutput(output) => {
+                write!(f, "Node {} has no output {}.", output.node, output.index)
+            }
+            ConnectionError::TypeMismatch {
+                input,
+                output,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Cannot connect {} output {} of node {} to {} input {} of node {}.",
+                found, output.index, output.node, expected, input.index, input.node
+            ),
+        }
+    }
+}
+
+// Reasons a builder cannot be compiled into a spell circuit
+#[derive(Clone, Debug)]
+pub enum CompileError {
+    UnconnectedInput {
+        node: usize,
+        index: usize,
+    },
+    MissingOutput,
+    Cycle(Vec<usize>),
+    TypeMismatch {
+        input: Output,
+        output: Output,
+        expected: Type,
+        found: Type,
+    },
+    Connection(ConnectionError),
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::UnconnectedInput { node, index } => {
+                write!(f, "Input {} of node {} is not connected.", index, node)
+            }
+            CompileError::MissingOutput => write!(f, "The circuit output is not connected."),
+            CompileError::Cycle(nodes) => {
+                write!(f, "The circuit has a cycle through nodes {:?}.", nodes)
+            }
+            CompileError::TypeMismatch {
+                input,
+                output,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Input {} of node {} expects {} but output {} of node {} gives {}.",
+                input.index, input.node, expected, output.index, output.node, found
+            ),
+            CompileError::Connection(err) => err.fmt(f),
+        }
+    }
 }
 
+#[derive(Clone)]
+pub struct BuilderNode {
+    pub inputs: Vec<Option<Output>>,
+    pub spell: Spell,
+}
+
+#[derive(Clone)]
 pub struct CircuitBuilder {
-    nodes: Vec<BuilderNode>,
-    output: Option<Output>,
+    pub nodes: Vec<BuilderNode>,
+    pub output: Option<Output>,
 }
 
 impl CircuitBuilder {
@@ -343,7 +1318,7 @@ impl CircuitBuilder {
         let nodes = spells
             .into_iter()
             .map(|spell| {
-                let inputs = vec![None; spell.num_inputs];
+                let inputs = vec![None; spell.num_inputs()];
                 BuilderNode { inputs, spell }
             })
             .collect::<Vec<BuilderNode>>();
@@ -353,76 +1328,342 @@ impl CircuitBuilder {
         }
     }
 
-    fn check_input(&self, input: &SpellInput) {
+    // Adds an unconnected node casting the given spell, returning its index
+    pub fn add_node(&mut self, spell: Spell) -> usize {
+        let inputs = vec![None; spell.num_inputs()];
+        self.nodes.push(BuilderNode { inputs, spell });
+        self.nodes.len() - 1
+    }
+
+    // The mana needed to cast the circuit in the builder, counting only the spells its output
+    // depends on
+    pub fn cost(&self) -> u32 {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let inputs = node
+                    .inputs
+                    .iter()
+                    .map(|input| input.as_ref().map(|output| output.node))
+                    .collect();
+                (&node.spell, inputs)
+            })
+            .collect::<Vec<_>>();
+        circuit_cost(&nodes, self.output.as_ref().map(|output| output.node))
+    }
+
+    // Inserts a node at the given index, moving the nodes after it and their connections one step up
+    pub fn insert_node(&mut self, index: usize, node: BuilderNode) {
+        let outputs = self
+            .nodes
+            .iter_mut()
+            .flat_map(|node| node.inputs.iter_mut().flatten())
+            .chain(self.output.iter_mut());
+        for output in outputs {
+            if output.node >= index {
+                output.node += 1;
+            }
+        }
+        self.nodes.insert(index, node);
+    }
+
+    // Removes a node, disconnecting everything connected to its outputs. Connections to later
+    // nodes are moved one step down. Returns the node and the inputs that were disconnected.
+    pub fn remove_node(&mut self, index: usize) -> (BuilderNode, Vec<SpellInput>) {
+        let mut disconnected = vec![];
+        for output in 0..self.nodes[index].spell.num_outputs() {
+            disconnected.extend(self.disconnect_output(&Output::new(index, output)));
+        }
+        let node = self.nodes.remove(index);
+        let outputs = self
+            .nodes
+            .iter_mut()
+            .flat_map(|node| node.inputs.iter_mut().flatten())
+            .chain(self.output.iter_mut());
+        for output in outputs {
+            if output.node > index {
+                output.node -= 1;
+            }
+        }
+        (node, disconnected)
+    }
+
+    // Replaces the spell of every node casting a spell with the same name and signature
+    pub fn update_spell(&mut self, spell: &Spell) {
+        for node in self.nodes.iter_mut() {
+            if node.spell.name == spell.name {
+                if node.spell.has_signature_of(spell) {
+                    node.spell = spell.clone();
+                } else {
+                    warn!(
+                        "Not updating spell {} in the builder since its ports changed.",
+                        spell.name
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_input(&self, input: &SpellInput) -> Result<(), ConnectionError> {
         if let SpellInput::Spell(ref input) = input {
             if input.node >= self.nodes.len() {
-                panic!(
-                    "Invalid input: {:?}. Circuit only has {:?} nodes.",
-                    input,
-                    self.nodes.len()
-                )
-            } else {
-                let node = &self.nodes[input.node];
-                if input.index >= node.spell.num_inputs {
-                    panic!(
-                        "Invalid output: {:?}. Node only has {:?} inputs.",
-                        input, node.spell.num_inputs
-                    )
-                }
+                return Err(ConnectionError::InvalidInput(input.clone()));
+            }
+            let node = &self.nodes[input.node];
+            if input.index >= node.spell.num_inputs() {
+                return Err(ConnectionError::InvalidInput(input.clone()));
             }
         }
+        Ok(())
     }
 
-    fn check_output(&self, output: &Output) {
+    fn check_output(&self, output: &Output) -> Result<(), ConnectionError> {
         if output.node >= self.nodes.len() {
-            panic!(
-                "Invalid output: {:?}. Circuit only has {:?} nodes.",
-                output,
-                self.nodes.len()
-            )
-        } else {
-            let node = &self.nodes[output.node];
-            if output.index >= node.spell.num_outputs {
-                panic!(
-                    "Invalid output: {:?}. Node only has {:?} outputs.",
-                    output, node.spell.num_outputs
-                )
-            }
+            return Err(ConnectionError::InvalidOutput(output.clone()));
+        }
+        let node = &self.nodes[output.node];
+        if output.index >= node.spell.num_outputs() {
+            return Err(ConnectionError::InvalidOutput(output.clone()));
         }
+        Ok(())
     }
 
-    pub fn connect_io(&mut self, input: SpellInput, output: Output) {
-        self.check_input(&input);
+    pub fn input_type(&self, input: &Output) -> Type {
+        self.nodes[input.node].spell.inputs[input.index]
+    }
+
+    pub fn output_type(&self, output: &Output) -> Type {
+        self.nodes[output.node].spell.outputs[output.index]
+    }
+
+    pub fn connect_io(&mut self, input: SpellInput, output: Output) -> Result<(), ConnectionError> {
+        self.check_input(&input)?;
+        self.check_output(&output)?;
         match input {
             SpellInput::Spell(input) => {
-                self.check_output(&output);
+                let expected = self.input_type(&input);
+                let found = self.output_type(&output);
+                if !expected.accepts(found) {
+                    return Err(ConnectionError::TypeMismatch {
+                        input,
+                        output,
+                        expected,
+                        found,
+                    });
+                }
                 let node = &mut self.nodes[input.node];
                 node.inputs[input.index] = Some(output);
-                // TODO: Check types and that no cycle is formed. Return bool based on this check.
             }
             SpellInput::CircuitOutput => {
                 self.output = Some(output);
             }
         }
+        Ok(())
+    }
+
+    // The output the given input is connected to
+    pub fn connected_output(&self, input: &SpellInput) -> Option<Output> {
+        match input {
+            SpellInput::Spell(input) => self
+                .nodes
+                .get(input.node)
+                .and_then(|node| node.inputs.get(input.index))
+                .cloned()
+                .flatten(),
+            SpellInput::CircuitOutput => self.output.clone(),
+        }
     }
 
-    fn convert_node(node: &BuilderNode) -> Option<CircuitNode> {
+    // Connects the input to the given output, or disconnects it when there is none
+    pub fn set_input(
+        &mut self,
+        input: &SpellInput,
+        output: Option<Output>,
+    ) -> Result<(), ConnectionError> {
+        match output {
+            Some(output) => self.connect_io(input.clone(), output),
+            None => {
+                self.disconnect_input(input);
+                Ok(())
+            }
+        }
+    }
+
+    // Clears the given input, returning the output it was connected to
+    pub fn disconnect_input(&mut self, input: &SpellInput) -> Option<Output> {
+        match input {
+            SpellInput::Spell(input) => self
+                .nodes
+                .get_mut(input.node)
+                .and_then(|node| node.inputs.get_mut(input.index))
+                .and_then(|input| input.take()),
+            SpellInput::CircuitOutput => self.output.take(),
+        }
+    }
+
+    // Clears every input connected to the given output, returning the inputs that were cleared
+    pub fn disconnect_output(&mut self, output: &Output) -> Vec<SpellInput> {
+        let inputs: Vec<SpellInput> = self
+            .connections()
+            .into_iter()
+            .filter(|(_, o)| o == output)
+            .map(|(input, _)| input)
+            .collect();
+        for input in inputs.iter() {
+            self.disconnect_input(input);
+        }
+        inputs
+    }
+
+    // Every connected input together with the output it is connected to
+    pub fn connections(&self) -> Vec<(SpellInput, Output)> {
+        let inputs = self.nodes.iter().enumerate().flat_map(|(i, node)| {
+            node.inputs
+                .iter()
+                .enumerate()
+                .filter_map(move |(j, output)| {
+                    output.clone().map(|output| (SpellInput::new(i, j), output))
+                })
+        });
+        inputs
+            .chain(
+                self.output
+                    .clone()
+                    .map(|output| (SpellInput::CircuitOutput, output)),
+            )
+            .collect()
+    }
+
+    // Every input of a node that is not connected to anything
+    pub fn unconnected_inputs(&self) -> Vec<Output> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| {
+                node.inputs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, output)| output.is_none())
+                    .map(move |(j, _)| Output::new(i, j))
+            })
+            .collect()
+    }
+
+    // Packages the builder as a single spell. The given inputs of nodes become the inputs of the
+    // spell and the given outputs become its outputs.
+    pub fn package(
+        &self,
+        name: &str,
+        inputs: Vec<Output>,
+        outputs: Vec<Output>,
+    ) -> Result<Spell, CompileError> {
+        if outputs.is_empty() {
+            return Err(CompileError::MissingOutput);
+        }
+        let mut builder = self.clone();
+        let mut arguments = vec![];
+        for input in inputs.iter() {
+            builder
+                .check_input(&SpellInput::Spell(input.clone()))
+                .map_err(CompileError::Connection)?;
+            let argument = builder.add_node(Spell::argument(self.input_type(input)));
+            builder
+                .connect_io(SpellInput::Spell(input.clone()), Output::new(argument, 0))
+                .map_err(CompileError::Connection)?;
+            arguments.push(argument);
+        }
+        for output in outputs.iter() {
+            builder
+                .check_output(output)
+                .map_err(CompileError::Connection)?;
+        }
+        let types = outputs.iter().map(|o| self.output_type(o)).collect();
+        let gather = builder.add_node(Spell::gather(types));
+        for (i, output) in outputs.iter().enumerate() {
+            builder
+                .connect_io(SpellInput::new(gather, i), output.clone())
+                .map_err(CompileError::Connection)?;
+        }
+        builder.output = Some(Output::new(gather, 0));
+        let circuit = builder.compile()?;
+        let spell = builder.nodes[gather].spell.clone();
+        let input_types = arguments
+            .iter()
+            .map(|&argument| builder.nodes[argument].spell.outputs[0])
+            .collect();
+        Ok(Spell::composite(
+            name,
+            input_types,
+            spell.outputs,
+            Composite { circuit, arguments },
+        ))
+    }
+
+    fn convert_node(&self, index: usize) -> Result<CircuitNode, CompileError> {
+        let node = &self.nodes[index];
         let inputs = node
             .inputs
-            .clone()
-            .into_iter()
-            .collect::<Option<Vec<Output>>>();
-        inputs.map(|inputs| CircuitNode::new(inputs, node.spell.clone()))
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let output = input.clone().ok_or(CompileError::UnconnectedInput {
+                    node: index,
+                    index: i,
+                })?;
+                let expected = node.spell.inputs[i];
+                let found = self.output_type(&output);
+                if !expected.accepts(found) {
+                    return Err(CompileError::TypeMismatch {
+                        input: Output::new(index, i),
+                        output,
+                        expected,
+                        found,
+                    });
+                }
+                Ok(output)
+            })
+            .collect::<Result<Vec<Output>, CompileError>>()?;
+        Ok(CircuitNode::new(inputs, node.spell.clone()))
     }
 
-    pub fn compile(&self) -> Option<SpellCircuit> {
-        self.output.clone().and_then(|output| {
-            let mut res: Option<Vec<CircuitNode>> = Some(vec![]);
-            self.nodes.iter().for_each(|node| {
-                res.as_mut()
-                    .map(|res| CircuitBuilder::convert_node(node).map(|node| res.push(node)));
-            });
-            res.map(|nodes| SpellCircuit::new(nodes, output))
-        })
+    // Depth first search along the input wires, returning the nodes of the first cycle found
+    fn find_cycle(&self) -> Option<Vec<usize>> {
+        fn visit(
+            builder: &CircuitBuilder,
+            node: usize,
+            visited: &mut Vec<bool>,
+            path: &mut Vec<usize>,
+        ) -> Option<Vec<usize>> {
+            if let Some(start) = path.iter().position(|&n| n == node) {
+                return Some(path[start..].to_vec());
+            }
+            if visited[node] {
+                return None;
+            }
+            visited[node] = true;
+            path.push(node);
+            for input in builder.nodes[node].inputs.iter().flatten() {
+                if let Some(cycle) = visit(builder, input.node, visited, path) {
+                    return Some(cycle);
+                }
+            }
+            path.pop();
+            None
+        }
+
+        let mut visited = vec![false; self.nodes.len()];
+        (0..self.nodes.len()).find_map(|node| visit(self, node, &mut visited, &mut vec![]))
+    }
+
+    pub fn compile(&self) -> Result<SpellCircuit, CompileError> {
+        let output = self.output.clone().ok_or(CompileError::MissingOutput)?;
+        let nodes = (0..self.nodes.len())
+            .map(|i| self.convert_node(i))
+            .collect::<Result<Vec<CircuitNode>, CompileError>>()?;
+        if let Some(cycle) = self.find_cycle() {
+            return Err(CompileError::Cycle(cycle));
+        }
+        Ok(SpellCircuit::new(nodes, output))
     }
 }
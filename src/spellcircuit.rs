@@ -1,15 +1,18 @@
 use crate::{
     effect::{Effect, Effects},
-    global_effect::{GlobalEffect, SelectRubble, SpawnUnit},
-    spell::{Spell, SpellState, UnitInfo, Value},
-    types::{Health, Position},
-    unit::Player,
+    global_effect::{GlobalEffect, SelectRubble},
+    spell::{Fizzle, Loop, Spell, SpellFunction, SpellState, UnitInfo, Value, MAX_ITERATIONS},
+    state::GameState,
+    turn::EffectsDone,
+    types::{Health, Position, UnitType},
+    unit::{Dying, Player},
 };
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 // Pointer to a specific output of a specific node in a spellcircuit
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Output {
     pub node: usize,
     pub index: usize,
@@ -21,10 +24,24 @@ impl Output {
     }
 }
 
+// Progress of a loop through its items
+#[derive(Clone)]
+pub struct LoopState {
+    items: Vec<Value>,
+    iteration: usize,
+    // Nodes of the body that are executed again on every iteration
+    body: Vec<usize>,
+}
+
 #[derive(Clone)]
 pub struct CircuitNode {
     inputs: Vec<Output>,
     pub outputs: Option<Vec<Value>>,
+    // The branch taken by a branching spell, once its conditions are evaluated
+    pub branch: Option<usize>,
+    pub looping: Option<LoopState>,
+    // The circuit of a composite spell while it is being executed
+    pub inner: Option<Box<SpellCircuit>>,
     spell: Spell,
 }
 
@@ -33,6 +50,9 @@ impl CircuitNode {
         Self {
             inputs,
             outputs: None,
+            branch: None,
+            looping: None,
+            inner: None,
             spell,
         }
     }
@@ -40,121 +60,441 @@ impl CircuitNode {
     pub fn is_computed(&self) -> bool {
         self.outputs.is_some()
     }
+
+    fn reset(&mut self) {
+        self.outputs = None;
+        self.branch = None;
+        self.looping = None;
+        self.inner = None;
+    }
+
+    pub fn inputs(&self) -> &[Output] {
+        &self.inputs
+    }
+
+    pub fn spell(&self) -> &Spell {
+        &self.spell
+    }
 }
 
-#[derive(Component)]
+// The effects of executing a single spell, or None if the circuit is complete
+pub type StepResult = Result<Option<(Vec<(Entity, Effect)>, Vec<GlobalEffect>)>, Fizzle>;
+
+#[derive(Component, Clone)]
 pub struct SpellCircuit {
     pub nodes: Vec<CircuitNode>,
     output: Output,
+    last_executed: Option<usize>,
 }
 
 impl SpellCircuit {
     pub fn new(nodes: Vec<CircuitNode>, output: Output) -> Self {
-        Self { nodes, output }
+        Self {
+            nodes,
+            output,
+            last_executed: None,
+        }
+    }
+
+    // The node whose spell was executed by the latest call to execute_next_spell
+    pub fn last_executed(&self) -> Option<usize> {
+        self.last_executed
+    }
+
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    // The mana needed to cast every spell the output of the circuit depends on
+    pub fn cost(&self) -> u32 {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let inputs = node.inputs.iter().map(|input| Some(input.node)).collect();
+                (&node.spell, inputs)
+            })
+            .collect::<Vec<_>>();
+        circuit_cost(&nodes, Some(self.output.node))
     }
 
     pub fn is_complete(&self) -> bool {
         self.nodes[self.output.node].is_computed()
     }
 
-    pub fn execute_next_spell(
-        &mut self,
-        s: &SpellState,
-    ) -> Option<(Vec<(Entity, Effect)>, Vec<GlobalEffect>)> {
+    // The outputs of the output node of a complete circuit
+    pub fn result(&self) -> Vec<Value> {
+        self.nodes[self.output.node]
+            .outputs
+            .clone()
+            .unwrap_or_default()
+    }
+
+    // Replaces an output with the value chosen for a selection, which may be in the circuit of
+    // a composite spell
+    pub fn set_selected(&mut self, select: &SelectRubble, value: Value) {
+        self.set_output(select.path(), select.output(), value);
+    }
+
+    fn set_output(&mut self, path: &[usize], output: &Output, value: Value) {
+        if let Some((node, rest)) = path.split_first() {
+            match self.nodes[*node].inner {
+                Some(ref mut inner) => inner.set_output(rest, output, value),
+                None => panic!(
+                    "Tried to select a value in unexecuted composite node {:?}.",
+                    node
+                ),
+            }
+        } else if let Some(ref mut outputs) = self.nodes[output.node].outputs {
+            outputs[output.index] = value;
+        } else {
+            panic!(
+                "Tried to replace output of unexecuted node {:?} with selected position.",
+                output.node
+            );
+        }
+    }
+
+    pub fn execute_next_spell(&mut self, s: &SpellState) -> StepResult {
         self.execute_next_spell_rec(s, &self.output.clone())
     }
 
-    fn execute_next_spell_rec(
+    fn value(&self, output: &Output) -> Value {
+        self.nodes[output.node].outputs.as_ref().unwrap()[output.index].clone()
+    }
+
+    // The inputs a node needs before it can be executed. Branching spells first need their
+    // conditions and then only the input of the branch taken. Loops need their conditions and
+    // execute their body themselves.
+    fn needed_inputs(&self, node: usize) -> Vec<Output> {
+        let node = &self.nodes[node];
+        match (&node.spell.function, node.branch) {
+            (SpellFunction::Branch(branch), None) => node.inputs[..branch.conditions].to_vec(),
+            (SpellFunction::Branch(branch), Some(choice)) => {
+                vec![node.inputs[branch.conditions + choice].clone()]
+            }
+            (SpellFunction::Loop(lp), _) => node.inputs[..lp.conditions].to_vec(),
+            _ => node.inputs.clone(),
+        }
+    }
+
+    // The nodes that still have to be executed before the output is computed
+    fn uncomputed_dependencies(&self, output: &Output) -> Vec<usize> {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = vec![output.node];
+        let mut nodes = vec![];
+        while let Some(node) = stack.pop() {
+            if visited[node] || self.nodes[node].is_computed() {
+                continue;
+            }
+            visited[node] = true;
+            nodes.push(node);
+            stack.extend(self.nodes[node].inputs.iter().map(|input| input.node));
+        }
+        nodes
+    }
+
+    // Executes the body of a loop once for every item, with the item as the element of the state.
+    // The body is cleared between iterations so its spells are cast again. Items that are units
+    // which died during the loop are skipped.
+    fn execute_loop(&mut self, s: &SpellState, output: &Output, lp: Loop) -> StepResult {
+        let node = output.node;
+        let body_output = self.nodes[node].inputs[lp.conditions].clone();
+        if self.nodes[node].looping.is_none() {
+            let conditions = self.nodes[node].inputs[..lp.conditions]
+                .iter()
+                .map(|o| self.value(o))
+                .collect::<Vec<Value>>();
+            let state = SpellState {
+                output: output.clone(),
+                ..s.clone()
+            };
+            let mut items = (lp.items)(&state, &conditions)?;
+            items.truncate(MAX_ITERATIONS);
+            let body = self.uncomputed_dependencies(&body_output);
+            self.nodes[node].looping = Some(LoopState {
+                items,
+                iteration: 0,
+                body,
+            });
+        }
+        loop {
+            let looping = self.nodes[node].looping.clone().unwrap();
+            let item = match looping.items.get(looping.iteration) {
+                Some(item) => item.clone(),
+                None => break,
+            };
+            let alive = match &item {
+                Value::Target(entity) => s.units.contains_key(entity),
+                _ => true,
+            };
+            if alive {
+                let state = SpellState {
+                    element: Some(item),
+                    ..s.clone()
+                };
+                if let Some(step) = self.execute_next_spell_rec(&state, &body_output)? {
+                    return Ok(Some(step));
+                }
+            }
+            let looping = self.nodes[node].looping.as_mut().unwrap();
+            looping.iteration += 1;
+            if looping.iteration < looping.items.len() {
+                for body_node in looping.body.clone() {
+                    self.nodes[body_node].reset();
+                }
+            }
+        }
+        let iterations = self.nodes[node].looping.as_ref().unwrap().items.len();
+        self.nodes[node].outputs = Some(vec![Value::Power(iterations as u32)]);
+        self.last_executed = Some(node);
+        Ok(Some((vec![], vec![])))
+    }
+
+    // Executes the circuit of a composite spell one spell at a time, then takes its result as the
+    // outputs of the node
+    fn execute_composite(
         &mut self,
         s: &SpellState,
-        output: &Output,
-    ) -> Option<(Vec<(Entity, Effect)>, Vec<GlobalEffect>)> {
-        if let Some(_) = self.nodes[output.node].outputs {
-            None
-        } else {
-            self.nodes[output.node]
+        node: usize,
+        composite: &Composite,
+    ) -> StepResult {
+        if self.nodes[node].inner.is_none() {
+            let inputs = self.nodes[node]
                 .inputs
-                .clone()
-                .into_iter()
-                .fold(None, |acc, o| {
-                    acc.or_else(|| self.execute_next_spell_rec(s, &o))
-                })
-                .or_else(|| {
-                    let inputs = self.nodes[output.node]
-                        .inputs
+                .iter()
+                .map(|o| self.value(o))
+                .collect();
+            self.nodes[node].inner = Some(Box::new(composite.instantiate(inputs)));
+        }
+        let inner = self.nodes[node].inner.as_mut().unwrap();
+        let step = inner.execute_next_spell(s)?;
+        let result = inner.result();
+        self.last_executed = Some(node);
+        match step {
+            Some((effects, globals)) => {
+                let globals = globals
+                    .into_iter()
+                    .map(|global| match global {
+                        GlobalEffect::Select(select) => {
+                            GlobalEffect::Select(select.nested_in(node))
+                        }
+                        global => global,
+                    })
+                    .collect();
+                Ok(Some((effects, globals)))
+            }
+            None => {
+                self.nodes[node].outputs = Some(result);
+                Ok(Some((vec![], vec![])))
+            }
+        }
+    }
+
+    fn execute_next_spell_rec(&mut self, s: &SpellState, output: &Output) -> StepResult {
+        if self.nodes[output.node].outputs.is_some() {
+            return Ok(None);
+        }
+        for input in self.needed_inputs(output.node).iter() {
+            if let Some(res) = self.execute_next_spell_rec(s, input)? {
+                return Ok(Some(res));
+            }
+        }
+        if let SpellFunction::Loop(lp) = self.nodes[output.node].spell.function {
+            return self.execute_loop(s, output, lp);
+        }
+        if let SpellFunction::Composite(ref composite) = self.nodes[output.node].spell.function {
+            let composite = composite.clone();
+            return self.execute_composite(s, output.node, &composite);
+        }
+        let state = &SpellState {
+            output: output.clone(),
+            ..s.clone()
+        };
+        if let SpellFunction::Branch(branch) = self.nodes[output.node].spell.function {
+            let node = &self.nodes[output.node];
+            let outputs = match node.branch {
+                Some(choice) => vec![self.value(&node.inputs[branch.conditions + choice])],
+                None => {
+                    let conditions = node.inputs[..branch.conditions]
                         .iter()
-                        .map(|o| self.nodes[o.node].outputs.clone().unwrap()[o.index].clone())
-                        .collect();
-                    let state = &SpellState {
-                        output: output.clone(),
-                        ..s.clone()
-                    };
-                    let res = (self.nodes[output.node].spell.function)(state, inputs);
-                    self.nodes[output.node].outputs = Some(res.0.clone());
-                    Some((res.1, res.2))
-                })
+                        .map(|o| self.value(o))
+                        .collect::<Vec<Value>>();
+                    let choice = (branch.condition)(state, &conditions)?;
+                    if branch.conditions + choice >= node.inputs.len() {
+                        return Err(Fizzle::BadInputs(conditions));
+                    }
+                    self.nodes[output.node].branch = Some(choice);
+                    // The chosen branch may need spells of its own before this one completes
+                    return self.execute_next_spell_rec(s, output);
+                }
+            };
+            self.nodes[output.node].outputs = Some(outputs);
+            self.last_executed = Some(output.node);
+            return Ok(Some((vec![], vec![])));
+        }
+        let inputs = self.nodes[output.node]
+            .inputs
+            .iter()
+            .map(|o| self.value(o))
+            .collect();
+        let (outputs, effects, globals) =
+            self.nodes[output.node].spell.function.call(state, inputs)?;
+        self.nodes[output.node].outputs = Some(outputs);
+        self.last_executed = Some(output.node);
+        Ok(Some((effects, globals)))
+    }
+}
+
+// The mana needed to cast a circuit, given the spell of every node, the nodes connected to its
+// inputs and the output node. Only spells the output depends on are cast, so only those are paid
+// for. Spells in the body of a loop are paid for once for every iteration the loop may run,
+// except those the conditions of the loop depend on, which are cast before the loop starts.
+pub fn circuit_cost(nodes: &[(&Spell, Vec<Option<usize>>)], output: Option<usize>) -> u32 {
+    // Marks every node reachable from the start nodes, without passing through marked nodes
+    fn mark(nodes: &[(&Spell, Vec<Option<usize>>)], start: &[Option<usize>], marked: &mut [bool]) {
+        let mut stack = start.iter().flatten().copied().collect::<Vec<usize>>();
+        while let Some(node) = stack.pop() {
+            if !marked[node] {
+                marked[node] = true;
+                stack.extend(nodes[node].1.iter().flatten());
+            }
+        }
+    }
+    let mut cast = vec![false; nodes.len()];
+    mark(nodes, &[output], &mut cast);
+    let mut multipliers = vec![1; nodes.len()];
+    for ((spell, inputs), _) in nodes.iter().zip(&cast).filter(|(_, cast)| **cast) {
+        if let SpellFunction::Loop(lp) = spell.function {
+            let mut before_loop = vec![false; nodes.len()];
+            mark(nodes, &inputs[..lp.conditions], &mut before_loop);
+            let mut body = before_loop.clone();
+            mark(nodes, &inputs[lp.conditions..], &mut body);
+            for (node, multiplier) in multipliers.iter_mut().enumerate() {
+                if body[node] && !before_loop[node] {
+                    *multiplier *= MAX_ITERATIONS as u32;
+                }
+            }
         }
     }
+    nodes
+        .iter()
+        .zip(multipliers)
+        .zip(cast)
+        .filter(|(_, cast)| *cast)
+        .map(|(((spell, _), multiplier), _)| spell.cost * multiplier)
+        .sum()
 }
 
-pub fn example_circuit() -> SpellCircuit {
-    let scout = CircuitNode::new(vec![], Spell::scout());
-    let spawn = CircuitNode::new(vec![Output::new(0, 0)], Spell::spawn_cobold());
-    let nodes = vec![scout, spawn];
-    let output = Output::new(1, 0);
-    SpellCircuit { nodes, output }
+// A circuit packaged as a single spell. The argument nodes take the inputs of the spell as their
+// outputs and the output node gathers its outputs.
+pub struct Composite {
+    pub circuit: SpellCircuit,
+    pub arguments: Vec<usize>,
+}
+
+impl Composite {
+    // A fresh copy of the circuit with the given inputs filled in
+    pub fn instantiate(&self, inputs: Vec<Value>) -> SpellCircuit {
+        let mut circuit = self.circuit.clone();
+        for (node, value) in self.arguments.iter().zip(inputs) {
+            circuit.nodes[*node].outputs = Some(vec![value]);
+        }
+        circuit
+    }
 }
 
 pub struct CircuitPlugin;
 
-struct EffectsDone(bool);
+// Sent when a spell in the active circuit fizzles, cancelling the rest of the circuit
+pub struct SpellFizzled(pub Fizzle);
 
 #[derive(Component)]
 pub struct Active;
 
+// When enabled, the active circuit pauses after every spell until told to step or continue
+#[derive(Default)]
+pub struct SpellDebugger {
+    pub enabled: bool,
+    step: bool,
+    running: bool,
+}
+
+impl SpellDebugger {
+    fn may_execute(&self) -> bool {
+        !self.enabled || self.step || self.running
+    }
+
+    fn finish(&mut self) {
+        self.step = false;
+        self.running = false;
+    }
+}
+
 impl Plugin for CircuitPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup)
-            .add_system_to_stage(CoreStage::PreUpdate, wait_for_effects)
-            .add_system(execute_spell_circuit_system);
+        app.add_event::<SpellFizzled>()
+            .init_resource::<SpellDebugger>()
+            .add_system(toggle_debugger_system)
+            .add_system_set(
+                SystemSet::on_update(GameState::Casting)
+                    .with_system(debugger_input_system)
+                    .with_system(execute_spell_circuit_system),
+            );
     }
 }
 
-fn setup(mut commands: Commands) {
-    commands.insert_resource(EffectsDone(true));
-    commands.spawn().insert(example_circuit()).insert(Active);
+// F9 toggles the debugger in every state, so it can be turned on before casting
+fn toggle_debugger_system(keys: Res<Input<KeyCode>>, mut debugger: ResMut<SpellDebugger>) {
+    if keys.just_pressed(KeyCode::F9) {
+        debugger.enabled = !debugger.enabled;
+        debugger.finish();
+    }
 }
 
-fn wait_for_effects(
-    mut effects: ResMut<EffectsDone>,
-    q_units: Query<&Effects>,
-    q_global: Query<Entity, Or<(With<SelectRubble>, With<SpawnUnit>)>>,
+// While casting, F10 steps, F5 continues and Escape aborts the cast
+fn debugger_input_system(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    mut debugger: ResMut<SpellDebugger>,
+    q_circuit: Query<Entity, With<Active>>,
+    q_select: Query<Entity, With<SelectRubble>>,
 ) {
-    if q_units.iter().find(|x| !x.0.is_empty()).is_none() && q_global.is_empty() {
-        effects.0 = true;
-    } else {
-        effects.0 = false;
+    if keys.just_pressed(KeyCode::F10) {
+        debugger.step = true;
+    }
+    if keys.just_pressed(KeyCode::F5) {
+        debugger.running = true;
+    }
+    if keys.just_pressed(KeyCode::Escape) {
+        for entity in q_circuit.iter().chain(q_select.iter()) {
+            commands.entity(entity).despawn();
+        }
+        debugger.finish();
     }
 }
 
 fn execute_spell_circuit_system(
     mut commands: Commands,
-    effects: ResMut<EffectsDone>,
+    effects: Res<EffectsDone>,
+    mut debugger: ResMut<SpellDebugger>,
     mut q_circuit: Query<(Entity, &mut SpellCircuit), With<Active>>,
-    mut q_units: Query<(Entity, &Health, &Position, &mut Effects)>,
+    mut q_units: Query<(Entity, &Health, &Position, &UnitType, &mut Effects), Without<Dying>>,
     q_rubble: Query<(Entity, &Position), Without<Health>>,
-    q_player: Query<Entity, With<Player>>,
+    q_player: Query<Entity, (With<Player>, Without<Dying>)>,
+    mut ev_fizzled: EventWriter<SpellFizzled>,
 ) {
-    if effects.0 {
+    if effects.0 && debugger.may_execute() {
         if let Ok((circuit_id, ref mut circuit)) = q_circuit.get_single_mut() {
             let mut units: HashMap<Entity, UnitInfo> = q_units
                 .iter()
-                .map(|(entity, health, pos, _)| {
+                .map(|(entity, health, pos, unit_type, _)| {
                     (
                         entity,
                         UnitInfo {
                             health: Some(health.0),
                             position: pos.0,
+                            unit_type: Some(*unit_type),
                         },
                     )
                 })
@@ -165,32 +505,99 @@ fn execute_spell_circuit_system(
                     UnitInfo {
                         health: None,
                         position: pos.0,
+                        unit_type: None,
                     },
                 );
             }
-            let player = q_player.single();
-            if let Some((new_effects, new_globals)) = circuit.execute_next_spell(&SpellState {
+            // A circuit whose caster died is cancelled
+            let player = match q_player.get_single() {
+                Ok(player) => player,
+                Err(_) => {
+                    commands.entity(circuit_id).despawn();
+                    debugger.finish();
+                    return;
+                }
+            };
+            match circuit.execute_next_spell(&SpellState {
                 output: Output::new(0, 0),
                 player,
+                element: None,
                 units,
             }) {
-                for (entity, effect) in new_effects.into_iter() {
-                    let entry = &mut q_units.get_mut(entity).unwrap();
-                    entry.3 .0.push_back(effect);
-                }
-                for effect in new_globals.into_iter() {
-                    match effect {
-                        GlobalEffect::Select(select) => {
-                            commands.spawn().insert(select);
-                        }
-                        GlobalEffect::Spawn(spawn) => {
-                            commands.spawn().insert(spawn);
+                Ok(Some((new_effects, new_globals))) => {
+                    debugger.step = false;
+                    if let Some((entity, _)) = new_effects
+                        .iter()
+                        .find(|(entity, _)| q_units.get(*entity).is_err())
+                    {
+                        ev_fizzled.send(SpellFizzled(Fizzle::MissingTarget(*entity)));
+                        commands.entity(circuit_id).despawn();
+                        debugger.finish();
+                        return;
+                    }
+                    for (entity, effect) in new_effects.into_iter() {
+                        let entry = &mut q_units.get_mut(entity).unwrap();
+                        entry.4 .0.push_back(effect);
+                    }
+                    for effect in new_globals.into_iter() {
+                        match effect {
+                            GlobalEffect::Select(select) => {
+                                commands.spawn().insert(select);
+                            }
+                            GlobalEffect::Spawn(spawn) => {
+                                commands.spawn().insert(spawn);
+                            }
                         }
                     }
                 }
-            } else {
-                commands.entity(circuit_id).despawn();
+                Ok(None) => {
+                    commands.entity(circuit_id).despawn();
+                    debugger.finish();
+                }
+                Err(reason) => {
+                    ev_fizzled.send(SpellFizzled(reason));
+                    commands.entity(circuit_id).despawn();
+                    debugger.finish();
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        spell::{SpellLibrary, MAX_ITERATIONS},
+        spellscript,
+    };
+
+    // The cost in the builder and of the compiled circuit, which must agree
+    fn cost(source: &str) -> u32 {
+        let builder = spellscript::parse(source, &SpellLibrary::with_builtins()).unwrap();
+        let cost = builder.cost();
+        assert_eq!(builder.compile().unwrap().cost(), cost);
+        cost
+    }
+
+    #[test]
+    fn pays_for_cast_spells() {
+        assert_eq!(cost("punch(constrict(player()))"), 4);
+    }
+
+    #[test]
+    fn ignores_spells_output_does_not_need() {
+        assert_eq!(cost("let _ = punch(player());\nplayer()"), 0);
+        assert_eq!(
+            cost("let p = player();\nlet _ = punch(p);\nconstrict(p)"),
+            1
+        );
+    }
+
+    #[test]
+    fn pays_for_every_iteration_of_loop_body() {
+        assert_eq!(
+            cost("repeat(air(), constrict(player()))"),
+            1 + MAX_ITERATIONS as u32
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{
+    effect::{Damage, Effect, Move},
+    global_effect::{GlobalEffect, SelectRubble, SpawnUnit},
+    spell::{Fizzle, Spell, SpellLibrary, SpellResult, SpellState, Type, Value},
+    spellbuilder::CircuitBuilder,
+    spellcircuit::Output,
+    types::{Health, Position, UnitType},
+    unit::Unit,
+};
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadState, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::Deserialize;
+
+// Where a value used by a spell definition comes from
+#[derive(Clone, Debug, Deserialize)]
+pub enum Source {
+    Input(usize),
+    Player,
+    Power(u32),
+    Empty,
+    // A position chosen by clicking on the map once the spell is cast
+    Selected,
+}
+
+// The primitive effects that defined spells are composed of
+#[derive(Clone, Debug, Deserialize)]
+pub enum Primitive {
+    Damage {
+        target: Source,
+        amount: i32,
+    },
+    Move {
+        unit: Source,
+        to: Source,
+    },
+    Spawn {
+        at: Source,
+        unit_type: UnitType,
+        health: i32,
+    },
+}
+
+// A spell loaded from a `.spell.ron` file in `assets/spells`
+#[derive(Clone, Debug, Deserialize, TypeUuid)]
+#[uuid = "5b0f7c5e-3f5d-4a43-9a3c-2f2e6c1b9d47"]
+pub struct SpellDefinition {
+    pub name: String,
+    // The name shown on cards, which defaults to `name`
+    #[serde(default)]
+    pub display_name: Option<String>,
+    pub description: String,
+    pub inputs: Vec<Type>,
+    pub outputs: Vec<Source>,
+    pub effects: Vec<Primitive>,
+    // Mana needed to cast the spell
+    #[serde(default)]
+    pub cost: u32,
+    #[serde(default)]
+    pub input_labels: Vec<String>,
+    #[serde(default)]
+    pub output_labels: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    InvalidInput { spell: String, index: usize },
+    NotATarget { spell: String, source: Source },
+}
+
+impl std::fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DefinitionError::InvalidInput { spell, index } => {
+                write!(f, "Spell {} refers to missing input {}", spell, index)
+            }
+            DefinitionError::NotATarget { spell, source } => {
+                write!(f, "Spell {} uses {:?} as a target", spell, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+impl SpellDefinition {
+    fn source_type(&self, source: &Source) -> Result<Type, DefinitionError> {
+        match source {
+            Source::Input(index) => {
+                self.inputs
+                    .get(*index)
+                    .copied()
+                    .ok_or_else(|| DefinitionError::InvalidInput {
+                        spell: self.name.clone(),
+                        index: *index,
+                    })
+            }
+            Source::Player | Source::Selected => Ok(Type::Target),
+            Source::Power(_) => Ok(Type::Power),
+            Source::Empty => Ok(Type::Empty),
+        }
+    }
+
+    fn check_target(&self, source: &Source) -> Result<(), DefinitionError> {
+        match (source, self.source_type(source)?) {
+            (Source::Selected, _)
+            | (_, Type::Power)
+            | (_, Type::Empty)
+            | (_, Type::Targets)
+            | (_, Type::Area)
+            | (_, Type::Any) => Err(DefinitionError::NotATarget {
+                spell: self.name.clone(),
+                source: source.clone(),
+            }),
+            (_, Type::Target) => Ok(()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), DefinitionError> {
+        for source in self.outputs.iter() {
+            self.source_type(source)?;
+        }
+        for effect in self.effects.iter() {
+            match effect {
+                Primitive::Damage { target, .. } => self.check_target(target)?,
+                Primitive::Move { unit, to } => {
+                    self.check_target(unit)?;
+                    self.check_target(to)?;
+                }
+                Primitive::Spawn { at, .. } => self.check_target(at)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn output_types(&self) -> Vec<Type> {
+        self.outputs
+            .iter()
+            .map(|source| self.source_type(source).unwrap_or(Type::Empty))
+            .collect()
+    }
+
+    fn resolve(&self, s: &SpellState, inputs: &[Value], source: &Source) -> Result<Value, Fizzle> {
+        match source {
+            Source::Input(index) => inputs
+                .get(*index)
+                .cloned()
+                .ok_or_else(|| Fizzle::BadInputs(inputs.to_vec())),
+            Source::Player => Ok(Value::Target(s.player)),
+            Source::Power(power) => Ok(Value::Power(*power)),
+            Source::Empty | Source::Selected => Ok(Value::Empty),
+        }
+    }
+
+    fn target(&self, s: &SpellState, inputs: &[Value], source: &Source) -> Result<Entity, Fizzle> {
+        match self.resolve(s, inputs, source)? {
+            Value::Target(entity) => Ok(entity),
+            _ => Err(Fizzle::BadInputs(inputs.to_vec())),
+        }
+    }
+
+    fn position(&self, s: &SpellState, inputs: &[Value], source: &Source) -> Result<Vec2, Fizzle> {
+        let entity = self.target(s, inputs, source)?;
+        s.units
+            .get(&entity)
+            .map(|unit| unit.position)
+            .ok_or(Fizzle::MissingTarget(entity))
+    }
+
+    pub fn cast(&self, s: &SpellState, inputs: Vec<Value>) -> SpellResult {
+        let mut effects = vec![];
+        let mut globals = vec![];
+        for effect in self.effects.iter() {
+            match effect {
+                Primitive::Damage { target, amount } => {
+                    let entity = self.target(s, &inputs, target)?;
+                    effects.push((entity, Effect::Damage(Damage::new(*amount))));
+                }
+                Primitive::Move { unit, to } => {
+                    let entity = self.target(s, &inputs, unit)?;
+                    let position = self.position(s, &inputs, to)?;
+                    effects.push((entity, Effect::Move(Move::new(Position(position)))));
+                }
+                Primitive::Spawn {
+                    at,
+                    unit_type,
+                    health,
+                } => {
+                    let unit = Unit {
+                        health: Health(*health),
+                        position: Position(self.position(s, &inputs, at)?),
+                        unit_type: *unit_type,
+                    };
+                    globals.push(GlobalEffect::Spawn(SpawnUnit::new(unit)));
+                }
+            }
+        }
+        let mut outputs = vec![];
+        for (index, source) in self.outputs.iter().enumerate() {
+            if let Source::Selected = source {
+                let output = Output::new(s.output.node, index);
+                globals.push(GlobalEffect::Select(SelectRubble::new(output)));
+            }
+            outputs.push(self.resolve(s, &inputs, source)?);
+        }
+        Ok((outputs, effects, globals))
+    }
+}
+
+#[derive(Default)]
+pub struct SpellDefinitionLoader;
+
+impl AssetLoader for SpellDefinitionLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definition = ron::de::from_bytes::<SpellDefinition>(bytes)?;
+            definition.validate()?;
+            load_context.set_default_asset(LoadedAsset::new(definition));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["spell.ron"]
+    }
+}
+
+pub struct SpellDefinitionPlugin;
+
+impl Plugin for SpellDefinitionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SpellDefinition>()
+            .init_asset_loader::<SpellDefinitionLoader>()
+            .insert_resource(SpellLibrary::with_builtins())
+            .init_resource::<DefinitionsLoaded>()
+            .add_startup_system(load_definitions)
+            .add_system(register_definitions);
+    }
+}
+
+// Keeps the loaded definitions alive so they can be hot reloaded
+pub struct SpellDefinitionHandles(pub Vec<HandleUntyped>);
+
+fn load_definitions(mut commands: Commands, asset_server: Res<AssetServer>) {
+    match asset_server.load_folder("spells") {
+        Ok(handles) => commands.insert_resource(SpellDefinitionHandles(handles)),
+        Err(err) => warn!("Could not load spell definitions: {:?}", err),
+    }
+}
+
+// Set once every definition found at startup has been added to the library, so spells can be
+// looked up by name from then on
+#[derive(Default)]
+pub struct DefinitionsLoaded(pub bool);
+
+fn register(
+    definition: &SpellDefinition,
+    library: &mut SpellLibrary,
+    builder: &mut CircuitBuilder,
+) {
+    let spell = Spell::from_definition(definition.clone());
+    builder.update_spell(&spell);
+    library.insert(spell);
+}
+
+fn register_definitions(
+    mut ev_asset: EventReader<AssetEvent<SpellDefinition>>,
+    definitions: Res<Assets<SpellDefinition>>,
+    asset_server: Res<AssetServer>,
+    handles: Option<Res<SpellDefinitionHandles>>,
+    mut loaded: ResMut<DefinitionsLoaded>,
+    mut library: ResMut<SpellLibrary>,
+    mut builder: ResMut<CircuitBuilder>,
+) {
+    for ev in ev_asset.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = ev {
+            if let Some(definition) = definitions.get(handle) {
+                register(definition, &mut library, &mut builder);
+            }
+        }
+    }
+    if loaded.0 {
+        return;
+    }
+    // Definitions are stored before their events are sent, so once the whole folder is done the
+    // ones without an event yet are added straight away. Files that failed to load are skipped.
+    if let Some(handles) = handles {
+        let done = handles.0.iter().all(|handle| {
+            matches!(
+                asset_server.get_load_state(handle),
+                LoadState::Loaded | LoadState::Failed
+            )
+        });
+        if !done {
+            return;
+        }
+        for handle in handles.0.iter() {
+            if let Some(definition) = definitions.get(handle) {
+                register(definition, &mut library, &mut builder);
+            }
+        }
+    }
+    loaded.0 = true;
+}
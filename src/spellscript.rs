@@ -0,0 +1,704 @@
+// A small text language for spell circuits. A script is a list of let statements followed by
+// the expression whose value is the output of the circuit, e.g.
+//
+//     let t = player();
+//     let (me, power) = introspection();
+//     punch(constrict(t))
+//
+// Every call creates a new node in the circuit. An `_` argument leaves that input unconnected and
+// `#` starts a comment running to the end of the line. The spellbook writes a script next to every
+// saved circuit and can load circuits back from scripts.
+use crate::{
+    spell::{Spell, SpellLibrary},
+    spellbuilder::{CircuitBuilder, ConnectionError, SpellInput},
+    spellcircuit::{Output, SpellCircuit},
+};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Let,
+    Underscore,
+    LParen,
+    RParen,
+    Comma,
+    Semicolon,
+    Equals,
+    End,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Let => write!(f, "`let`"),
+            Token::Underscore => write!(f, "`_`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Semicolon => write!(f, "`;`"),
+            Token::Equals => write!(f, "`=`"),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ScriptErrorKind {
+    UnexpectedChar(char),
+    UnexpectedToken {
+        expected: String,
+        found: String,
+    },
+    UnknownSpell(String),
+    UnknownVariable(String),
+    WrongArgumentCount {
+        spell: String,
+        expected: usize,
+        found: usize,
+    },
+    WrongBindingCount {
+        spell: String,
+        expected: usize,
+        found: usize,
+    },
+    Connection(ConnectionError),
+}
+
+impl std::fmt::Display for ScriptErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScriptErrorKind::UnexpectedChar(c) => write!(f, "unexpected character `{}`", c),
+            ScriptErrorKind::UnexpectedToken { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            ScriptErrorKind::UnknownSpell(name) => write!(f, "unknown spell `{}`", name),
+            ScriptErrorKind::UnknownVariable(name) => write!(f, "unknown variable `{}`", name),
+            ScriptErrorKind::WrongArgumentCount {
+                spell,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} arguments but was given {}",
+                spell, expected, found
+            ),
+            ScriptErrorKind::WrongBindingCount {
+                spell,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` has {} outputs but {} names were bound",
+                spell, expected, found
+            ),
+            ScriptErrorKind::Connection(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// An error in a script, pointing at the line and column (both starting at 1) where it occurred
+#[derive(Clone, Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ScriptErrorKind,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    line: usize,
+    column: usize,
+}
+
+impl Location {
+    fn error(self, kind: ScriptErrorKind) -> ScriptError {
+        ScriptError {
+            line: self.line,
+            column: self.column,
+            kind,
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Location)>, ScriptError> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut location = Location { line: 1, column: 1 };
+    while let Some(&c) = chars.peek() {
+        let start = location;
+        let token = match c {
+            '\n' => {
+                chars.next();
+                location.line += 1;
+                location.column = 1;
+                continue;
+            }
+            c if c.is_whitespace() => None,
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            ',' => Some(Token::Comma),
+            ';' => Some(Token::Semicolon),
+            '=' => Some(Token::Equals),
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    word.push(c);
+                    chars.next();
+                    location.column += 1;
+                }
+                let token = match word.as_str() {
+                    "let" => Token::Let,
+                    "_" => Token::Underscore,
+                    _ => Token::Ident(word),
+                };
+                tokens.push((token, start));
+                continue;
+            }
+            c => return Err(start.error(ScriptErrorKind::UnexpectedChar(c))),
+        };
+        chars.next();
+        location.column += 1;
+        if let Some(token) = token {
+            tokens.push((token, start));
+        }
+    }
+    tokens.push((Token::End, location));
+    Ok(tokens)
+}
+
+// A reference to a value in a script. Holes leave the input they are passed to unconnected.
+enum Reference {
+    Value(Output),
+    Hole,
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token, Location)>,
+    position: usize,
+    library: &'a SpellLibrary,
+    builder: CircuitBuilder,
+    variables: HashMap<String, Output>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &(Token, Location) {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> (Token, Location) {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<Location, ScriptError> {
+        let (token, location) = self.next();
+        if token == expected {
+            Ok(location)
+        } else {
+            Err(location.error(ScriptErrorKind::UnexpectedToken {
+                expected: expected.to_string(),
+                found: token.to_string(),
+            }))
+        }
+    }
+
+    fn program(&mut self) -> Result<(), ScriptError> {
+        while self.peek().0 == Token::Let {
+            self.statement()?;
+        }
+        if self.peek().0 != Token::End {
+            let location = self.peek().1;
+            match self.expression()? {
+                Reference::Value(output) => self
+                    .builder
+                    .connect_io(SpellInput::CircuitOutput, output)
+                    .map_err(|err| location.error(ScriptErrorKind::Connection(err)))?,
+                Reference::Hole => {}
+            }
+        }
+        self.expect(Token::End)?;
+        Ok(())
+    }
+
+    // let <pattern> = <call>;
+    fn statement(&mut self) -> Result<(), ScriptError> {
+        self.expect(Token::Let)?;
+        let pattern = self.pattern()?;
+        self.expect(Token::Equals)?;
+        let location = self.peek().1;
+        let node = match self.next() {
+            (Token::Ident(name), location) if self.peek().0 == Token::LParen => {
+                self.call(name, location)?
+            }
+            (token, location) => {
+                return Err(location.error(ScriptErrorKind::UnexpectedToken {
+                    expected: "a spell call".to_string(),
+                    found: token.to_string(),
+                }))
+            }
+        };
+        self.expect(Token::Semicolon)?;
+        let spell = &self.builder.nodes[node].spell;
+        let names = match pattern {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+        if names.len() != spell.num_outputs() {
+            return Err(location.error(ScriptErrorKind::WrongBindingCount {
+                spell: spell.name.clone(),
+                expected: spell.num_outputs(),
+                found: names.len(),
+            }));
+        }
+        for (index, name) in names.into_iter().enumerate() {
+            if let Some(name) = name {
+                self.variables.insert(name, Output::new(node, index));
+            }
+        }
+        Ok(())
+    }
+
+    // Either `_`, a single name or a parenthesized list of names and `_`
+    fn pattern(&mut self) -> Result<Option<Vec<Option<String>>>, ScriptError> {
+        match self.next() {
+            (Token::Underscore, _) => Ok(None),
+            (Token::Ident(name), _) => Ok(Some(vec![Some(name)])),
+            (Token::LParen, _) => {
+                let mut names = vec![];
+                loop {
+                    match self.next() {
+                        (Token::Ident(name), _) => names.push(Some(name)),
+                        (Token::Underscore, _) => names.push(None),
+                        (token, location) => {
+                            return Err(location.error(ScriptErrorKind::UnexpectedToken {
+                                expected: "a name".to_string(),
+                                found: token.to_string(),
+                            }))
+                        }
+                    }
+                    match self.next() {
+                        (Token::Comma, _) => {}
+                        (Token::RParen, _) => return Ok(Some(names)),
+                        (token, location) => {
+                            return Err(location.error(ScriptErrorKind::UnexpectedToken {
+                                expected: "`,` or `)`".to_string(),
+                                found: token.to_string(),
+                            }))
+                        }
+                    }
+                }
+            }
+            (token, location) => Err(location.error(ScriptErrorKind::UnexpectedToken {
+                expected: "a name or pattern".to_string(),
+                found: token.to_string(),
+            })),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Reference, ScriptError> {
+        match self.next() {
+            (Token::Underscore, _) => Ok(Reference::Hole),
+            (Token::Ident(name), location) if self.peek().0 == Token::LParen => {
+                let node = self.call(name, location)?;
+                let spell = &self.builder.nodes[node].spell;
+                if spell.num_outputs() != 1 {
+                    return Err(location.error(ScriptErrorKind::WrongBindingCount {
+                        spell: spell.name.clone(),
+                        expected: spell.num_outputs(),
+                        found: 1,
+                    }));
+                }
+                Ok(Reference::Value(Output::new(node, 0)))
+            }
+            (Token::Ident(name), location) => self
+                .variables
+                .get(&name)
+                .cloned()
+                .map(Reference::Value)
+                .ok_or_else(|| location.error(ScriptErrorKind::UnknownVariable(name))),
+            (token, location) => Err(location.error(ScriptErrorKind::UnexpectedToken {
+                expected: "an expression".to_string(),
+                found: token.to_string(),
+            })),
+        }
+    }
+
+    // Parses the arguments of a call to the named spell and adds it as a node
+    fn call(&mut self, name: String, location: Location) -> Result<usize, ScriptError> {
+        let spell = self
+            .library
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| location.error(ScriptErrorKind::UnknownSpell(name.clone())))?;
+        self.expect(Token::LParen)?;
+        let mut arguments = vec![];
+        if self.peek().0 != Token::RParen {
+            loop {
+                let location = self.peek().1;
+                arguments.push((self.expression()?, location));
+                match self.next() {
+                    (Token::Comma, _) => {}
+                    (Token::RParen, _) => break,
+                    (token, location) => {
+                        return Err(location.error(ScriptErrorKind::UnexpectedToken {
+                            expected: "`,` or `)`".to_string(),
+                            found: token.to_string(),
+                        }))
+                    }
+                }
+            }
+        } else {
+            self.next();
+        }
+        if arguments.len() != spell.num_inputs() {
+            return Err(location.error(ScriptErrorKind::WrongArgumentCount {
+                spell: name,
+                expected: spell.num_inputs(),
+                found: arguments.len(),
+            }));
+        }
+        let node = self.builder.add_node(spell);
+        for (index, (argument, location)) in arguments.into_iter().enumerate() {
+            if let Reference::Value(output) = argument {
+                self.builder
+                    .connect_io(SpellInput::new(node, index), output)
+                    .map_err(|err| location.error(ScriptErrorKind::Connection(err)))?;
+            }
+        }
+        Ok(node)
+    }
+}
+
+pub fn parse(source: &str, library: &SpellLibrary) -> Result<CircuitBuilder, ScriptError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        library,
+        builder: CircuitBuilder::from_spells(vec![]),
+        variables: HashMap::new(),
+    };
+    parser.program()?;
+    Ok(parser.builder)
+}
+
+struct Printer<'a> {
+    nodes: Vec<(&'a Spell, Vec<Option<Output>>)>,
+    uses: Vec<usize>,
+    inlined: Vec<bool>,
+}
+
+impl<'a> Printer<'a> {
+    fn is_inlined(&self, node: usize) -> bool {
+        self.inlined[node]
+    }
+
+    fn variable(&self, output: &Output) -> String {
+        if self.nodes[output.node].0.num_outputs() == 1 {
+            format!("v{}", output.node)
+        } else {
+            format!("v{}_{}", output.node, output.index)
+        }
+    }
+
+    fn reference(&self, output: &Option<Output>) -> String {
+        match output {
+            Some(output) if self.is_inlined(output.node) => self.call(output.node),
+            Some(output) => self.variable(output),
+            None => "_".to_string(),
+        }
+    }
+
+    fn call(&self, node: usize) -> String {
+        let (spell, inputs) = &self.nodes[node];
+        let arguments = inputs
+            .iter()
+            .map(|input| self.reference(input))
+            .collect::<Vec<String>>();
+        format!("{}({})", spell.name, arguments.join(", "))
+    }
+
+    // Orders the nodes so that every node comes after the nodes it takes inputs from
+    fn order(&self) -> Vec<usize> {
+        fn visit(printer: &Printer, node: usize, visited: &mut Vec<bool>, order: &mut Vec<usize>) {
+            if visited[node] {
+                return;
+            }
+            visited[node] = true;
+            for input in printer.nodes[node].1.iter().flatten() {
+                visit(printer, input.node, visited, order);
+            }
+            order.push(node);
+        }
+
+        let mut visited = vec![false; self.nodes.len()];
+        let mut order = vec![];
+        for node in 0..self.nodes.len() {
+            visit(self, node, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn print(&self, output: Option<&Output>) -> String {
+        let mut lines = vec![];
+        for node in self.order() {
+            if self.is_inlined(node) {
+                continue;
+            }
+            let spell = self.nodes[node].0;
+            let pattern = if self.uses[node] == 0 {
+                "_".to_string()
+            } else if spell.num_outputs() == 1 {
+                self.variable(&Output::new(node, 0))
+            } else {
+                let names = (0..spell.num_outputs())
+                    .map(|index| self.variable(&Output::new(node, index)))
+                    .collect::<Vec<String>>();
+                format!("({})", names.join(", "))
+            };
+            lines.push(format!("let {} = {};", pattern, self.call(node)));
+        }
+        if let Some(output) = output {
+            lines.push(self.reference(&Some(output.clone())));
+        }
+        lines.join("\n")
+    }
+
+    fn new(nodes: Vec<(&'a Spell, Vec<Option<Output>>)>, output: Option<&Output>) -> Self {
+        let mut uses = vec![0; nodes.len()];
+        for input in nodes
+            .iter()
+            .flat_map(|(_, inputs)| inputs.iter().flatten())
+            .chain(output)
+        {
+            uses[input.node] += 1;
+        }
+        let mut printer = Self {
+            nodes,
+            uses,
+            inlined: vec![],
+        };
+        // Nodes with a single output that is used once are written inline where they are used,
+        // unless they feed a node earlier in the order, which only happens along a cycle.
+        let order = printer.order();
+        let mut position = vec![0; order.len()];
+        for (i, node) in order.iter().enumerate() {
+            position[*node] = i;
+        }
+        printer.inlined = (0..printer.nodes.len())
+            .map(|node| printer.nodes[node].0.num_outputs() == 1 && printer.uses[node] == 1)
+            .collect();
+        for (consumer, (_, inputs)) in printer.nodes.iter().enumerate() {
+            for input in inputs.iter().flatten() {
+                if position[input.node] >= position[consumer] {
+                    printer.inlined[input.node] = false;
+                }
+            }
+        }
+        printer
+    }
+}
+
+pub fn print_builder(builder: &CircuitBuilder) -> String {
+    let nodes = builder
+        .nodes
+        .iter()
+        .map(|node| (&node.spell, node.inputs.clone()))
+        .collect();
+    Printer::new(nodes, builder.output.as_ref()).print(builder.output.as_ref())
+}
+
+pub fn print_circuit(circuit: &SpellCircuit) -> String {
+    let nodes = circuit
+        .nodes
+        .iter()
+        .map(|node| {
+            (
+                node.spell(),
+                node.inputs().iter().cloned().map(Some).collect(),
+            )
+        })
+        .collect();
+    Printer::new(nodes, Some(circuit.output())).print(Some(circuit.output()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> SpellLibrary {
+        SpellLibrary::with_builtins()
+    }
+
+    // The spell and inputs of every node, and the circuit output
+    fn shape(builder: &CircuitBuilder) -> (Vec<(String, Vec<Option<Output>>)>, Option<Output>) {
+        let nodes = builder
+            .nodes
+            .iter()
+            .map(|node| (node.spell.name.clone(), node.inputs.clone()))
+            .collect();
+        (nodes, builder.output.clone())
+    }
+
+    fn builder(
+        spells: Vec<Spell>,
+        connections: &[((usize, usize), (usize, usize))],
+    ) -> CircuitBuilder {
+        let mut builder = CircuitBuilder::from_spells(spells);
+        for ((node, index), (from, output)) in connections {
+            builder
+                .connect_io(SpellInput::new(*node, *index), Output::new(*from, *output))
+                .unwrap();
+        }
+        builder
+    }
+
+    fn round_trip(builder: &CircuitBuilder) {
+        let text = print_builder(builder);
+        let parsed = parse(&text, &library()).unwrap_or_else(|err| panic!("{}\n{}", err, text));
+        assert_eq!(shape(&parsed), shape(builder), "\n{}", text);
+        assert_eq!(print_builder(&parsed), text);
+    }
+
+    fn error(source: &str) -> ScriptError {
+        match parse(source, &library()) {
+            Ok(_) => panic!("`{}` parsed", source),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn round_trip_chain() {
+        let mut builder = builder(
+            vec![Spell::player(), Spell::constrict(), Spell::punch()],
+            &[((1, 0), (0, 0)), ((2, 0), (1, 0))],
+        );
+        builder
+            .connect_io(SpellInput::CircuitOutput, Output::new(2, 0))
+            .unwrap();
+        assert_eq!(print_builder(&builder), "punch(constrict(player()))");
+        round_trip(&builder);
+    }
+
+    #[test]
+    fn round_trip_shared_and_multiple_outputs() {
+        let mut builder = builder(
+            vec![
+                Spell::introspection(),
+                Spell::constrict(),
+                Spell::empowered_punch(),
+            ],
+            &[((1, 0), (0, 0)), ((2, 0), (1, 0)), ((2, 1), (0, 1))],
+        );
+        builder
+            .connect_io(SpellInput::CircuitOutput, Output::new(2, 0))
+            .unwrap();
+        round_trip(&builder);
+    }
+
+    #[test]
+    fn round_trip_unconnected() {
+        let builder = builder(
+            vec![Spell::player(), Spell::empowered_punch(), Spell::scout()],
+            &[((1, 0), (0, 0))],
+        );
+        let text = print_builder(&builder);
+        assert!(text.contains("empowered_punch(player(), _)"), "{}", text);
+        round_trip(&builder);
+    }
+
+    #[test]
+    fn round_trip_compiled_circuit() {
+        let source = "let p = player();\nlet _ = punch(p);\nconstrict(p)";
+        let circuit = parse(source, &library()).unwrap().compile().unwrap();
+        let text = print_circuit(&circuit);
+        let parsed = parse(&text, &library()).unwrap();
+        assert_eq!(print_circuit(&parsed.compile().unwrap()), text);
+    }
+
+    #[test]
+    fn comments_and_whitespace() {
+        let source = "# the caster\nlet p = player();   # bound once\n\n  constrict( p )\n";
+        let parsed = parse(source, &library()).unwrap();
+        assert_eq!(print_builder(&parsed), "constrict(player())");
+    }
+
+    #[test]
+    fn unknown_spell_location() {
+        let err = error("let p = player();\nfoo(p)");
+        assert_eq!((err.line, err.column), (2, 1));
+        assert!(matches!(err.kind, ScriptErrorKind::UnknownSpell(name) if name == "foo"));
+    }
+
+    #[test]
+    fn unknown_variable_location() {
+        let err = error("let p = player();\n  punch(q)");
+        assert_eq!((err.line, err.column), (2, 9));
+        assert!(matches!(err.kind, ScriptErrorKind::UnknownVariable(name) if name == "q"));
+    }
+
+    #[test]
+    fn unexpected_char_location() {
+        let err = error("let p = player();\npunch(p) $");
+        assert_eq!((err.line, err.column), (2, 10));
+        assert!(matches!(err.kind, ScriptErrorKind::UnexpectedChar('$')));
+    }
+
+    #[test]
+    fn missing_semicolon_location() {
+        let err = error("let p = player()\npunch(p)");
+        assert_eq!((err.line, err.column), (2, 1));
+        assert!(matches!(err.kind, ScriptErrorKind::UnexpectedToken { .. }));
+    }
+
+    #[test]
+    fn argument_count_location() {
+        let err = error("let p = player();\nlet _ = empowered_punch(p);");
+        assert_eq!((err.line, err.column), (2, 9));
+        assert!(matches!(
+            err.kind,
+            ScriptErrorKind::WrongArgumentCount {
+                expected: 2,
+                found: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn binding_count_location() {
+        let err = error("let p = introspection();");
+        assert_eq!((err.line, err.column), (1, 9));
+        assert!(matches!(
+            err.kind,
+            ScriptErrorKind::WrongBindingCount {
+                expected: 2,
+                found: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn type_mismatch_location() {
+        let err = error("let (me, power) = introspection();\npunch(power)");
+        assert_eq!((err.line, err.column), (2, 7));
+        assert!(matches!(err.kind, ScriptErrorKind::Connection(_)));
+    }
+}
@@ -0,0 +1,41 @@
+use crate::spellcircuit::Active;
+use bevy::prelude::*;
+
+// The mode the game is in. Systems that take input only run in the states they belong to, so
+// input meant for one mode does not reach another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GameState {
+    Explore,
+    BuildSpell,
+    // Pushed on top of the state the cast started from. When the cast ends it is replaced by the
+    // enemy turn, which returns to that state once every enemy has acted.
+    Casting,
+    EnemyTurn,
+    Victory,
+    Defeat,
+}
+
+pub struct StatePlugin;
+
+impl Plugin for StatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(GameState::Explore)
+            .add_system_set(SystemSet::on_update(GameState::Explore).with_system(start_casting))
+            .add_system_set(SystemSet::on_update(GameState::BuildSpell).with_system(start_casting))
+            .add_system_set(SystemSet::on_update(GameState::Casting).with_system(finish_casting));
+    }
+}
+
+fn start_casting(mut state: ResMut<State<GameState>>, q_active: Query<&Active>) {
+    if !q_active.is_empty() {
+        // Casting takes precedence over any other transition queued this frame
+        state.overwrite_push(GameState::Casting).unwrap();
+    }
+}
+
+// Casting a circuit takes the turn of the player
+fn finish_casting(mut state: ResMut<State<GameState>>, q_active: Query<&Active>) {
+    if q_active.is_empty() {
+        state.overwrite_set(GameState::EnemyTurn).unwrap();
+    }
+}
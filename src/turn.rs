@@ -0,0 +1,109 @@
+use crate::{
+    ai::Behavior,
+    effect::Effects,
+    global_effect::{GlobalEffect, SelectRubble, SpawnUnit},
+    simulation::WorldSnapshot,
+    spell::UnitInfo,
+    state::GameState,
+    types::{Health, Position, UnitType},
+    unit::{Dying, Player},
+};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+// Whether every queued effect has finished, so the next spell or actor may go
+pub struct EffectsDone(pub bool);
+
+// The units still to act this enemy turn, in order
+#[derive(Default)]
+pub struct TurnQueue(pub VecDeque<Entity>);
+
+// The number of turns completed in the encounter, counting each enemy turn as the end of a turn
+#[derive(Default)]
+pub struct TurnCount(pub u32);
+
+pub struct TurnPlugin;
+
+impl Plugin for TurnPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(EffectsDone(true))
+            .init_resource::<TurnQueue>()
+            .init_resource::<TurnCount>()
+            .add_system_to_stage(CoreStage::PreUpdate, wait_for_effects)
+            .add_system_set(SystemSet::on_enter(GameState::EnemyTurn).with_system(start_enemy_turn))
+            .add_system_set(SystemSet::on_update(GameState::EnemyTurn).with_system(enemy_turn));
+    }
+}
+
+fn wait_for_effects(
+    mut effects: ResMut<EffectsDone>,
+    q_units: Query<&Effects>,
+    q_global: Query<Entity, Or<(With<SelectRubble>, With<SpawnUnit>)>>,
+) {
+    effects.0 = q_units.iter().all(|x| x.0.is_empty()) && q_global.is_empty();
+}
+
+// Every unit with a behavior that is alive at the start of the enemy turn acts once
+fn start_enemy_turn(
+    mut queue: ResMut<TurnQueue>,
+    q_units: Query<Entity, (With<Behavior>, Without<Dying>)>,
+) {
+    let mut actors = q_units.iter().collect::<Vec<Entity>>();
+    actors.sort_by_key(|entity| entity.id());
+    queue.0 = actors.into();
+}
+
+// Lets the next unit act once the effects of the previous one are done, handing the turn back to
+// the player after the last one
+fn enemy_turn(
+    mut commands: Commands,
+    effects: Res<EffectsDone>,
+    mut state: ResMut<State<GameState>>,
+    mut queue: ResMut<TurnQueue>,
+    mut turns: ResMut<TurnCount>,
+    q_units: Query<(Entity, &Health, &Position, &UnitType), Without<Dying>>,
+    q_behavior: Query<&Behavior, Without<Dying>>,
+    q_player: Query<Entity, (With<Player>, Without<Dying>)>,
+    mut q_effects: Query<&mut Effects>,
+) {
+    if !effects.0 {
+        return;
+    }
+    let actor = match queue.0.pop_front() {
+        Some(actor) => actor,
+        None => {
+            turns.0 += 1;
+            state.overwrite_pop().unwrap();
+            return;
+        }
+    };
+    // The actor or the player may have died since the turn started
+    let (behavior, player) = match (q_behavior.get(actor), q_player.get_single()) {
+        (Ok(behavior), Ok(player)) => (behavior, player),
+        _ => return,
+    };
+    let units = q_units
+        .iter()
+        .map(|(entity, health, position, unit_type)| {
+            (
+                entity,
+                UnitInfo {
+                    health: Some(health.0),
+                    position: position.0,
+                    unit_type: Some(*unit_type),
+                },
+            )
+        })
+        .collect();
+    let (new_effects, new_globals) = behavior.decide(actor, &WorldSnapshot { player, units });
+    for (entity, effect) in new_effects {
+        if let Ok(mut effects) = q_effects.get_mut(entity) {
+            effects.0.push_back(effect);
+        }
+    }
+    for global in new_globals {
+        if let GlobalEffect::Spawn(spawn) = global {
+            commands.spawn().insert(spawn);
+        }
+    }
+}
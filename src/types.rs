@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use serde::Deserialize;
 
 #[derive(Component, Clone, Debug)]
 pub struct Position(pub Vec2);
@@ -6,8 +7,55 @@ pub struct Position(pub Vec2);
 #[derive(Component, Clone, Debug)]
 pub struct Health(pub i32);
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+// Spent to cast spells and regenerated over time
+#[derive(Component, Clone, Debug)]
+pub struct Mana {
+    pub current: f32,
+    pub max: f32,
+    // Mana regenerated at the end of every turn
+    pub regen: f32,
+}
+
+impl Mana {
+    pub fn new(max: f32, regen: f32) -> Self {
+        Self {
+            current: max,
+            max,
+            regen,
+        }
+    }
+
+    pub fn can_afford(&self, cost: u32) -> bool {
+        self.current >= cost as f32
+    }
+
+    // Spends the mana if there is enough of it
+    pub fn spend(&mut self, cost: u32) -> bool {
+        let affordable = self.can_afford(cost);
+        if affordable {
+            self.current -= cost as f32;
+        }
+        affordable
+    }
+}
+
+#[derive(Component, Copy, Clone, Hash, Eq, PartialEq, Debug, Deserialize)]
 pub enum UnitType {
     Player,
     Kobold,
 }
+
+impl UnitType {
+    // Whether units of this type leave a corpse behind when they die
+    pub fn leaves_corpse(&self) -> bool {
+        matches!(self, UnitType::Kobold)
+    }
+
+    // Health of units of this type unless they are given their own
+    pub fn default_health(&self) -> i32 {
+        match self {
+            UnitType::Player => 30,
+            UnitType::Kobold => 10,
+        }
+    }
+}
@@ -5,12 +5,14 @@ use crate::{
     mouseclick::MouseClick,
     resources::DefaultFont,
     spellbuilder::SpellBuilderUI,
+    state::GameState,
+    turn::EffectsDone,
     types::Position,
+    unit::Player,
 };
 
 /// This example illustrates how to create a button that changes color and text based on its
 /// interaction state.
-
 const NORMAL_BUTTON: Color = Color::rgb(0.15, 0.15, 0.15);
 const HOVERED_BUTTON: Color = Color::rgb(0.25, 0.25, 0.25);
 const PRESSED_BUTTON: Color = Color::rgb(0.35, 0.75, 0.35);
@@ -23,10 +25,17 @@ impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<ButtonClick>()
             .add_startup_system(setup)
-            .add_system(move_button_system)
-            .add_system(select_target_system)
-            .add_system(update_move_menu_system)
-            .add_system(toggle_ui_system);
+            .add_system_set(
+                SystemSet::on_update(GameState::Explore)
+                    .with_system(move_button_system)
+                    .with_system(select_target_system)
+                    .with_system(update_move_menu_system)
+                    .with_system(toggle_mode_system),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::BuildSpell).with_system(toggle_mode_system),
+            )
+            .add_system(show_ui_system);
     }
 }
 
@@ -44,7 +53,7 @@ fn select_target_system(
                 unit = Some(e);
             }
         }
-        if let Some(_) = unit {
+        if unit.is_some() {
             prep.unit = unit;
         } else {
             prep.target = Some(click_pos);
@@ -69,19 +78,21 @@ fn move_button_system(
         (&Interaction, &mut UiColor, &MovePrep),
         (Changed<Interaction>, With<Button>),
     >,
-    mut effects_query: Query<&mut Effects>,
+    mut effects_query: Query<&mut Effects, With<Player>>,
+    mut effects_done: ResMut<EffectsDone>,
+    mut state: ResMut<State<GameState>>,
 ) {
     for (interaction, mut color, prep) in interaction_query.iter_mut() {
         match *interaction {
             Interaction::Clicked => {
                 *color = PRESSED_BUTTON.into();
-                if let Some(m) = prep.compile() {
-                    if let Some(e) = prep.unit {
-                        effects_query
-                            .get_mut(e)
-                            .unwrap()
-                            .0
-                            .push_back(Effect::Move(m))
+                // Only the player can be moved, which takes their turn
+                if let (Some(m), Some(e)) = (prep.compile(), prep.unit) {
+                    if let Ok(mut effects) = effects_query.get_mut(e) {
+                        effects.0.push_back(Effect::Move(m));
+                        // The enemy turn can start this frame, before the move is noticed
+                        effects_done.0 = false;
+                        state.overwrite_push(GameState::EnemyTurn).unwrap();
                     }
                 }
             }
@@ -98,39 +109,44 @@ fn move_button_system(
 #[derive(Component)]
 struct MoveUI;
 
-enum UI {
-    Move,
-    SpellBuilder,
+// M switches between exploring and building spells
+fn toggle_mode_system(keys: Res<Input<KeyCode>>, mut state: ResMut<State<GameState>>) {
+    if keys.just_released(KeyCode::M) {
+        let next = match state.current() {
+            GameState::Explore => GameState::BuildSpell,
+            _ => GameState::Explore,
+        };
+        // Ignored when a cast already queued its own transition this frame
+        state.set(next).unwrap_or_default();
+    }
 }
 
-impl Default for UI {
-    fn default() -> Self {
-        UI::Move
+fn set_display(style: &mut Mut<Style>, visible: bool) {
+    let display = if visible {
+        Display::Flex
+    } else {
+        Display::None
+    };
+    if style.display != display {
+        style.display = display;
     }
 }
 
-fn toggle_ui_system(
-    mut current_ui: Local<UI>,
-    keys: Res<Input<KeyCode>>,
+// Shows the menu of the current mode. A cast keeps the menu of the mode it was started from.
+fn show_ui_system(
+    state: Res<State<GameState>>,
     mut move_query: Query<&mut Style, (With<MoveUI>, Without<SpellBuilderUI>)>,
     mut builder_query: Query<&mut Style, (With<SpellBuilderUI>, Without<MoveUI>)>,
 ) {
-    if keys.just_released(KeyCode::M) {
-        let move_style = &mut move_query.single_mut();
-        let builder_style = &mut builder_query.single_mut();
-        match *current_ui {
-            UI::Move => {
-                *current_ui = UI::SpellBuilder;
-                move_style.display = Display::None;
-                builder_style.display = Display::Flex;
-            }
-            UI::SpellBuilder => {
-                *current_ui = UI::Move;
-                move_style.display = Display::Flex;
-                builder_style.display = Display::None;
-            }
-        }
+    if !state.is_changed() {
+        return;
     }
+    let in_stack = |s: GameState| *state.current() == s || state.inactives().contains(&s);
+    set_display(&mut move_query.single_mut(), in_stack(GameState::Explore));
+    set_display(
+        &mut builder_query.single_mut(),
+        in_stack(GameState::BuildSpell),
+    );
 }
 
 fn setup(mut commands: Commands, font: Res<DefaultFont>) {
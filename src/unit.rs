@@ -1,7 +1,8 @@
 use crate::{
     effect::Effects,
-    resources::{DefaultFont, TextureHandles},
-    types::{Health, Position, UnitType},
+    resources::TextureHandles,
+    turn::TurnCount,
+    types::{Health, Mana, Position, UnitType},
 };
 use bevy::prelude::*;
 
@@ -9,10 +10,14 @@ pub struct UnitPlugin;
 
 impl Plugin for UnitPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_units)
+        app.add_event::<UnitDied>()
             .add_system(update_effect)
             .add_system(update_transform)
-            .add_system(update_health_text);
+            .add_system(update_health_text)
+            .add_system(regenerate_mana)
+            .add_system(update_mana_text)
+            .add_system(death_system)
+            .add_system(dying_system);
     }
 }
 
@@ -25,7 +30,7 @@ fn update_transform(mut query: Query<(&Position, &mut Transform), Changed<Positi
 // Runs the effects in each units effects queue one after the other
 fn update_effect(mut query: Query<(&mut Health, &mut Position, &mut Effects)>) {
     for (mut health, mut pos, mut effects) in query.iter_mut() {
-        if let Some(effect) = effects.0.get(0) {
+        if let Some(effect) = effects.0.front_mut() {
             if effect.update(&mut health, &mut pos) {
                 effects.0.pop_front();
             }
@@ -33,6 +38,95 @@ fn update_effect(mut query: Query<(&mut Health, &mut Position, &mut Effects)>) {
     }
 }
 
+// Sent when the health of a unit reaches zero
+pub struct UnitDied {
+    pub entity: Entity,
+    pub unit_type: UnitType,
+    pub position: Vec2,
+}
+
+// Seconds a dead unit takes to fade away
+const DEATH_SECONDS: f32 = 1.;
+
+// Marks a unit whose health reached zero. It no longer takes part in spells and fades away
+// before it is despawned.
+#[derive(Component)]
+pub struct Dying(Timer);
+
+// What is left of a dead unit. Like rubble, spells can target it.
+#[derive(Component)]
+pub struct Corpse(pub UnitType);
+
+fn death_system(
+    mut commands: Commands,
+    mut ev_died: EventWriter<UnitDied>,
+    query: Query<(Entity, &Health, &Position, &UnitType), Without<Dying>>,
+) {
+    for (entity, health, position, unit_type) in query.iter() {
+        if health.0 <= 0 {
+            // Without effects and an animation timer the unit stops acting and stands still
+            commands
+                .entity(entity)
+                .remove::<Effects>()
+                .remove::<Timer>()
+                .insert(Dying(Timer::from_seconds(DEATH_SECONDS, false)));
+            ev_died.send(UnitDied {
+                entity,
+                unit_type: *unit_type,
+                position: position.0,
+            });
+        }
+    }
+}
+
+fn dying_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(
+        Entity,
+        &mut Dying,
+        &mut TextureAtlasSprite,
+        &Handle<TextureAtlas>,
+        &Position,
+        &UnitType,
+    )>,
+) {
+    for (entity, mut dying, mut sprite, atlas, position, unit_type) in query.iter_mut() {
+        dying.0.tick(time.delta());
+        sprite.color.set_a(dying.0.percent_left());
+        if dying.0.finished() {
+            commands.entity(entity).despawn_recursive();
+            if unit_type.leaves_corpse() {
+                spawn_corpse(&mut commands, atlas.clone(), position.clone(), *unit_type);
+            }
+        }
+    }
+}
+
+fn spawn_corpse(
+    commands: &mut Commands,
+    atlas: Handle<TextureAtlas>,
+    position: Position,
+    unit_type: UnitType,
+) {
+    commands
+        .spawn_bundle(SpriteSheetBundle {
+            texture_atlas: atlas,
+            sprite: TextureAtlasSprite {
+                color: Color::GRAY,
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: position.0.extend(0.),
+                rotation: Quat::from_rotation_z(std::f32::consts::FRAC_PI_2),
+                scale: Vec3::splat(2.),
+            },
+            ..Default::default()
+        })
+        .insert(position)
+        .insert(Corpse(unit_type));
+}
+
 #[derive(Component)]
 struct HealthText;
 
@@ -43,12 +137,63 @@ fn update_health_text(
     for (health, children) in q_unit.iter() {
         for &child in children.iter() {
             if let Ok(mut text) = q_text.get_mut(child) {
-                text.sections[0].value = health.0.to_string();
+                text.sections[0].value = health.0.max(0).to_string();
+            }
+        }
+    }
+}
+
+const PLAYER_MANA: f32 = 10.;
+const PLAYER_MANA_REGEN: f32 = 3.;
+
+// Mana regenerates once for every completed turn, so waiting does not refill it
+fn regenerate_mana(turns: Res<TurnCount>, mut last_turn: Local<u32>, mut query: Query<&mut Mana>) {
+    // Restarting an encounter counts turns from zero again
+    let completed = turns.0.saturating_sub(*last_turn);
+    *last_turn = turns.0;
+    if completed == 0 {
+        return;
+    }
+    for mut mana in query.iter_mut() {
+        mana.current = (mana.current + mana.regen * completed as f32).min(mana.max);
+    }
+}
+
+#[derive(Component)]
+struct ManaText;
+
+fn update_mana_text(
+    mut q_text: Query<&mut Text, With<ManaText>>,
+    q_unit: Query<(&Mana, &Children), Changed<Mana>>,
+) {
+    for (mana, children) in q_unit.iter() {
+        for &child in children.iter() {
+            if let Ok(mut text) = q_text.get_mut(child) {
+                text.sections[0].value = mana_label(mana);
             }
         }
     }
 }
 
+fn mana_label(mana: &Mana) -> String {
+    format!("{}", mana.current.floor())
+}
+
+fn mana_text(mana: &Mana, font: Handle<Font>) -> Text {
+    Text::with_section(
+        mana_label(mana),
+        TextStyle {
+            font,
+            font_size: 10.0,
+            color: Color::BLUE,
+        },
+        TextAlignment {
+            vertical: VerticalAlign::Center,
+            horizontal: HorizontalAlign::Center,
+        },
+    )
+}
+
 fn health_text(health: i32, font: Handle<Font>) -> Text {
     Text::with_section(
         health.to_string(),
@@ -67,7 +212,7 @@ fn health_text(health: i32, font: Handle<Font>) -> Text {
 #[derive(Component)]
 pub struct Player;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Unit {
     pub health: Health,
     pub position: Position,
@@ -78,6 +223,7 @@ pub struct Unit {
 struct UnitBundle {
     health: Health,
     position: Position,
+    unit_type: UnitType,
     effects: Effects,
     #[bundle]
     sprite: SpriteSheetBundle,
@@ -89,7 +235,7 @@ pub fn spawn_unit(
     texture_handles: &TextureHandles,
     font: Handle<Font>,
     unit: Unit,
-) {
+) -> Entity {
     let entity = commands
         .spawn_bundle(UnitBundle {
             health: unit.health.clone(),
@@ -104,12 +250,13 @@ pub fn spawn_unit(
                 ..Default::default()
             },
             position: unit.position,
+            unit_type: unit.unit_type,
             timer: Timer::from_seconds(0.1, true),
         })
         .with_children(|parent| {
             parent
                 .spawn_bundle(Text2dBundle {
-                    text: health_text(unit.health.0, font),
+                    text: health_text(unit.health.0, font.clone()),
                     transform: Transform::from_translation(Vec3::new(0., -20., 0.)),
                     ..Default::default()
                 })
@@ -117,43 +264,21 @@ pub fn spawn_unit(
         })
         .id();
     if unit.unit_type == UnitType::Player {
-        commands.entity(entity).insert(Player);
+        let mana = Mana::new(PLAYER_MANA, PLAYER_MANA_REGEN);
+        let text = mana_text(&mana, font);
+        commands
+            .entity(entity)
+            .insert(Player)
+            .insert(mana)
+            .with_children(|parent| {
+                parent
+                    .spawn_bundle(Text2dBundle {
+                        text,
+                        transform: Transform::from_translation(Vec3::new(0., -28., 0.)),
+                        ..Default::default()
+                    })
+                    .insert(ManaText);
+            });
     }
-}
-
-fn setup_units(
-    mut commands: Commands,
-    texture_handles: Res<TextureHandles>,
-    font: Res<DefaultFont>,
-) {
-    let enemy_positions = vec![
-        Position(Vec2::new(-200., 160.)),
-        Position(Vec2::new(0., 120.)),
-        Position(Vec2::new(220., 20.)),
-        Position(Vec2::new(-70., -180.)),
-        Position(Vec2::new(-145., 280.)),
-    ];
-
-    spawn_unit(
-        &mut commands,
-        &texture_handles,
-        font.0.clone(),
-        Unit {
-            health: Health(30),
-            position: Position(Vec2::ZERO),
-            unit_type: UnitType::Player,
-        },
-    );
-    enemy_positions.into_iter().for_each(|pos| {
-        spawn_unit(
-            &mut commands,
-            &texture_handles,
-            font.0.clone(),
-            Unit {
-                health: Health(10),
-                position: pos,
-                unit_type: UnitType::Kobold,
-            },
-        )
-    })
+    entity
 }